@@ -2,23 +2,34 @@ use nadi_core::nadi_plugin::nadi_plugin;
 
 #[nadi_plugin]
 mod datafill {
-    use super::utils::{DataFillMethod, ExprFunc};
+    use super::utils::{
+        gap_length, gap_lengths, parse_frequency, sample_gaps, DataFillMethod, ExprFunc, FillChain,
+        MaxGap, Regression, ERROR_METRICS, EXPERIMENT_METHODS,
+    };
+    use chrono::Datelike;
     use nadi_core::abi_stable::external_types::RMutex;
-    use nadi_core::abi_stable::std_types::RArc;
+    use nadi_core::abi_stable::std_types::{RArc, RString};
     use nadi_core::anyhow::{self, bail, Context};
+    use nadi_core::attrs::{Date, DateTime};
     use nadi_core::nadi_plugin::{network_func, node_func};
     use nadi_core::prelude::*;
     use nadi_core::string_template::Template;
     use nadi_core::timeseries;
     use polars::prelude::*;
     use rand::{rngs::StdRng, SeedableRng};
-    use std::collections::HashMap;
+    use std::collections::{HashMap, HashSet};
     use std::fs::File;
     use std::io::{BufWriter, Write};
     use std::ops::Mul;
     use std::path::PathBuf;
 
-    #[node_func(method = DataFillMethod::Linear, dtype = "Floats")]
+    /// Load the timeseries from the CSV file and fill the gaps
+    ///
+    /// The dates are reindexed at `frequency` and the gaps filled with
+    /// the `method`, chained with `->` and limited per method, e.g.
+    /// `linear(max 3) -> iratio:area(max 2d) -> climatology`, with the
+    /// value flags saved in the `{name}_flag` timeseries.
+    #[node_func(method = FillChain::from(DataFillMethod::Linear), dtype = "Floats")]
     fn load_csv_fill(
         node: &mut NodeInner,
         /// Name of the timeseries
@@ -30,32 +41,87 @@ mod datafill {
         /// Names of date column and value column
         columns: (String, String),
         // columns: Option<(String, String)>,
-        /// Method to use for data filling: forward/backward/linear
-        method: DataFillMethod,
-        /// DataType to load into timeseries
+        /// Method to use for data filling: forward/backward/linear/nearest/iratio:var/oratio:var/regression[:nodes]/climatology[:options]
+        method: FillChain,
+        /// DataType to load into timeseries: Floats/Integers/Strings/Booleans/Dates/DateTimes
         dtype: String,
+        /// Frequency of the timeseries, inferred from the dates by default
+        frequency: Option<String>,
+        /// Longest gap to fill, in timesteps (e.g. 5) or duration (e.g. 2d)
+        max_gap: Option<String>,
     ) -> anyhow::Result<()> {
+        let max_gap = max_gap
+            .map(|m| MaxGap::parse(&m))
+            .transpose()
+            .map_err(anyhow::Error::msg)?;
+        let method = method.with_max_gap(max_gap);
+        method.check_dtype(&dtype).map_err(anyhow::Error::msg)?;
         let (dtcol, valcol) = columns;
-        let file = node.render(&file)?;
-        let df = LazyCsvReader::new(file)
+        let csv = node.render(&file)?;
+        let df = LazyCsvReader::new(csv)
             .with_has_header(true)
             .with_try_parse_dates(true)
-            .finish()?;
+            .finish()?
+            .with_column(col(&dtcol).cast(DataType::Datetime(TimeUnit::Milliseconds, None)))
+            .filter(col(&dtcol).is_not_null())
+            .sort([dtcol.as_str()], Default::default())
+            .unique_stable(Some(vec![dtcol.clone()]), UniqueKeepStrategy::First);
+        let times = timestamps(&df, &dtcol)?;
+        let step = match frequency {
+            Some(f) => parse_frequency(&f).map_err(anyhow::Error::msg)?,
+            None => infer_step(&times).context(format!(
+                "Node {}: cannot infer the frequency, give it as an argument",
+                node.name()
+            ))?,
+        };
+        let start = *times.first().context("No minimum date")?;
+        let end = *times.last().context("No maximum date")?;
+        let on_calendar = times.iter().filter(|t| (*t - start) % step == 0).count();
+        if on_calendar < times.len() {
+            eprintln!(
+                "WARN Node {}: {} dates not matching the frequency are dropped",
+                node.name(),
+                times.len() - on_calendar
+            );
+        }
+        let inserted = ((end - start) / step + 1) as usize - on_calendar;
+        node.set_attr(
+            &format!("{name}_inserted"),
+            Attribute::Integer(inserted as i64),
+        );
+        let df = calendar(&dtcol, &times, step)?.join(
+            df,
+            [col(&dtcol)],
+            [col(&dtcol)],
+            JoinArgs::new(JoinType::Left),
+        );
+        // values are filled as floats, and dates as milliseconds
+        let value = match dtype.as_str() {
+            "Strings" => col(&valcol).cast(DataType::String),
+            "Booleans" => col(&valcol).cast(DataType::Boolean),
+            "Dates" | "DateTimes" => col(&valcol)
+                .cast(DataType::Datetime(TimeUnit::Milliseconds, None))
+                .cast(DataType::Int64),
+            _ => col(&valcol).cast(DataType::Float64),
+        };
+        let data = df.clone().with_column(value).collect()?;
+        let missing: Vec<bool> = data
+            .column(&valcol)?
+            .is_null()
+            .into_no_null_iter()
+            .collect();
+        let (filled, filled_by) = fill_chain(
+            node,
+            &name,
+            &method,
+            data,
+            &valcol,
+            Some((&dtcol, step)),
+            |nodes, data| {
+                Ok(join_neighbours(data.lazy(), nodes, &file, (&dtcol, &valcol))?.collect()?)
+            },
+        )?;
         // converting the dates to timeline that all timeseries can share
-        let dates = df
-            .clone()
-            .lazy()
-            .select([col(&dtcol)
-                .dt()
-                .timestamp(TimeUnit::Milliseconds)
-                .alias("timestamp")])
-            .collect()?;
-
-        let dt_col = dates.column("timestamp")?;
-        let start = dt_col.min::<i64>()?.context("No minimum date")?;
-        let end = dt_col.max::<i64>()?.context("No maximum date")?;
-        let step = (end - start) / dt_col.len() as i64;
-
         let dates = df
             .clone()
             .lazy()
@@ -71,26 +137,610 @@ mod datafill {
             dates.into_iter().map(String::from).collect(),
             &timefmt,
         );
-        let fill: ExprFunc = method.polars_fn()?;
+        let s = filled.column(&valcol)?;
+        let unfilled = format!("Node {}: {dtype} gaps couldn't be filled", node.name());
         let values: timeseries::Series = match dtype.as_str() {
             "Floats" => {
-                let df2 = df
-                    .clone()
-                    .lazy()
-                    .select([fill(col(&valcol).cast(DataType::Float64))])
-                    .collect()?;
-                let s = df2.column(&valcol)?;
-                let v: Vec<f64> = s.f64()?.into_no_null_iter().collect();
+                // gaps the method couldn't fill are saved as NaN
+                let v: Vec<f64> = s
+                    .f64()?
+                    .into_iter()
+                    .map(|v| v.unwrap_or(f64::NAN))
+                    .collect();
                 timeseries::Series::floats(v)
             }
+            "Integers" => {
+                let v: Vec<i64> = s
+                    .f64()?
+                    .into_iter()
+                    .map(|v| {
+                        v.map(|v| v.round() as i64)
+                            .with_context(|| unfilled.clone())
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                timeseries::Series::integers(v)
+            }
+            "Strings" => {
+                let v: Vec<RString> = s
+                    .str()?
+                    .into_iter()
+                    .map(|v| v.map(RString::from).with_context(|| unfilled.clone()))
+                    .collect::<anyhow::Result<_>>()?;
+                timeseries::Series::strings(v)
+            }
+            "Booleans" => {
+                let v: Vec<bool> = s
+                    .bool()?
+                    .into_iter()
+                    .map(|v| v.with_context(|| unfilled.clone()))
+                    .collect::<anyhow::Result<_>>()?;
+                timeseries::Series::booleans(v)
+            }
+            "Dates" | "DateTimes" => {
+                let v: Vec<chrono::DateTime<chrono::FixedOffset>> = s
+                    .i64()?
+                    .into_iter()
+                    .map(|v| {
+                        v.and_then(chrono::DateTime::from_timestamp_millis)
+                            .map(|d| d.fixed_offset())
+                            .with_context(|| unfilled.clone())
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                if dtype == "Dates" {
+                    timeseries::Series::dates(
+                        v.into_iter()
+                            .map(|d| Date::new(d.year() as u16, d.month() as u8, d.day() as u8))
+                            .collect(),
+                    )
+                } else {
+                    timeseries::Series::datetimes(v.into_iter().map(DateTime::from).collect())
+                }
+            }
             _ => bail!("{dtype} is not supported or is not a recognized data type"),
         };
         let timeline = RArc::new(RMutex::new(timeline));
         let ts = nadi_core::timeseries::TimeSeries::new(timeline.clone(), values);
         node.set_ts(&name, ts);
+        let flags = fill_flags(node, &name, &missing, &filled_by, &method.labels());
+        let ts = nadi_core::timeseries::TimeSeries::new(timeline.clone(), flags);
+        node.set_ts(&format!("{name}_flag"), ts);
+        Ok(())
+    }
+
+    /// Fill the nulls in a timeseries already loaded in the node
+    ///
+    /// The values that are NaN (or equal to `nodata`) are treated as
+    /// missing, and the ones that couldn't be filled are saved as
+    /// NaN. The methods that use the neighbouring nodes take their
+    /// timeseries of the same name, which should have the same
+    /// timeline.
+    ///
+    /// Like in `load_csv_fill`, the flags of the values are saved in
+    /// the `{output}_flag` timeseries, along with the fill counts in
+    /// the `{output}_*` attributes. The methods can be chained and
+    /// limited with `max_gap` the same way too.
+    /// The climatology method and the limits given as durations use
+    /// the dates of the timeline, parsed with its date time format.
+    #[node_func(method = FillChain::from(DataFillMethod::Linear))]
+    fn ts_fill(
+        node: &mut NodeInner,
+        /// Name of the timeseries
+        name: String,
+        /// Method to use for data filling: forward/backward/linear/nearest/iratio:var/oratio:var/regression[:nodes]/climatology[:options]
+        method: FillChain,
+        /// Name of the filled timeseries, replaces the original by default
+        output: Option<String>,
+        /// Value that represents missing data, other than NaN
+        nodata: Option<f64>,
+        /// Longest gap to fill, in timesteps (e.g. 5) or duration (e.g. 2d)
+        max_gap: Option<String>,
+    ) -> anyhow::Result<()> {
+        let max_gap = max_gap
+            .map(|m| MaxGap::parse(&m))
+            .transpose()
+            .map_err(anyhow::Error::msg)?;
+        let method = method.with_max_gap(max_gap);
+        let (values, timeline) = {
+            let ts = node.try_ts(&name).ok().context(format!(
+                "Node {} doesn't have timeseries {name:?}",
+                node.name()
+            ))?;
+            (ts_floats(ts, nodata)?, ts.timeline().clone())
+        };
+        let missing: Vec<bool> = values.iter().map(Option::is_none).collect();
+        let mut columns = vec![Series::new("value".into(), values)];
+        let time = if method.needs_dates() {
+            let times = timeline_millis(&timeline)?;
+            let step = infer_step(&times).context("Could not find the timestep of the timeline")?;
+            columns.push(
+                Series::new("time".into(), times)
+                    .cast(&DataType::Datetime(TimeUnit::Milliseconds, None))?,
+            );
+            Some(("time", step))
+        } else {
+            None
+        };
+        let df = DataFrame::new(columns)?;
+        let (df, filled_by) = fill_chain(node, &name, &method, df, "value", time, |nodes, df| {
+            let mut columns = df.get_columns().to_vec();
+            for (i, n) in nodes.iter().enumerate() {
+                let n = n.lock();
+                let ts = n.try_ts(&name).ok().context(format!(
+                    "Node {} doesn't have timeseries {name:?}",
+                    n.name()
+                ))?;
+                if !same_timeline(ts.timeline(), &timeline) {
+                    bail!(
+                        "Timeseries {name:?} of node {} has a different timeline",
+                        n.name()
+                    );
+                }
+                let vals = ts_floats(ts, nodata)?;
+                columns.push(Series::new(neighbour_column(i).as_str().into(), vals));
+            }
+            Ok(DataFrame::new(columns)?)
+        })?;
+        let filled = df.column("value")?;
+        let filled: Vec<f64> = filled
+            .f64()?
+            .into_iter()
+            .map(|v| v.unwrap_or(f64::NAN))
+            .collect();
+        let output = output.unwrap_or(name);
+        let ts = timeseries::TimeSeries::new(timeline.clone(), timeseries::Series::floats(filled));
+        node.set_ts(&output, ts);
+        let flags = fill_flags(node, &output, &missing, &filled_by, &method.labels());
+        let ts = timeseries::TimeSeries::new(timeline, flags);
+        node.set_ts(&format!("{output}_flag"), ts);
         Ok(())
     }
 
+    /// Flags of the values: `observed`, `missing` or the fill method
+    ///
+    /// Also saves the number of filled and missing values, and the
+    /// length of the longest filled gap as attributes.
+    fn fill_flags(
+        node: &mut NodeInner,
+        name: &str,
+        missing: &[bool],
+        filled_by: &[Option<usize>],
+        labels: &[&str],
+    ) -> timeseries::Series {
+        let filled: Vec<bool> = filled_by.iter().map(Option::is_some).collect();
+        let unfilled: Vec<bool> = missing
+            .iter()
+            .zip(&filled)
+            .map(|(m, f)| *m && !*f)
+            .collect();
+        let flags: Vec<RString> = missing
+            .iter()
+            .zip(filled_by)
+            .map(|(m, f)| {
+                RString::from(match (m, f) {
+                    (false, _) => "observed",
+                    (true, Some(k)) => labels[*k],
+                    (true, None) => "missing",
+                })
+            })
+            .collect();
+        if labels.len() > 1 {
+            for (k, label) in labels.iter().enumerate() {
+                let n = filled_by.iter().filter(|f| **f == Some(k)).count();
+                node.set_attr(
+                    &format!("{name}_filled_{label}"),
+                    Attribute::Integer(n as i64),
+                );
+            }
+        }
+        let count = |v: &[bool]| v.iter().filter(|b| **b).count() as i64;
+        let longest = gap_lengths(filled.iter().copied())
+            .into_iter()
+            .max()
+            .unwrap_or(0);
+        node.set_attr(
+            &format!("{name}_filled"),
+            Attribute::Integer(count(&filled)),
+        );
+        node.set_attr(
+            &format!("{name}_missing"),
+            Attribute::Integer(count(&unfilled)),
+        );
+        node.set_attr(
+            &format!("{name}_longest_gap"),
+            Attribute::Integer(longest as i64),
+        );
+        timeseries::Series::strings(flags)
+    }
+
+    /// Whether the timelines have the same times
+    fn same_timeline(
+        tl1: &RArc<RMutex<timeseries::TimeLineInner>>,
+        tl2: &RArc<RMutex<timeseries::TimeLineInner>>,
+    ) -> bool {
+        if RArc::ptr_eq(tl1, tl2) {
+            return true;
+        }
+        let (tl1, tl2) = (tl1.lock(), tl2.lock());
+        tl1.str_values().into_iter().eq(tl2.str_values())
+    }
+
+    /// Timestamps of the timeline in milliseconds
+    fn timeline_millis(
+        timeline: &RArc<RMutex<timeseries::TimeLineInner>>,
+    ) -> anyhow::Result<Vec<i64>> {
+        let timeline = timeline.lock();
+        let fmt = timeline.datetimefmt().to_string();
+        timeline
+            .str_values()
+            .into_iter()
+            .map(|t| {
+                // dates are taken at midnight
+                let dt = match chrono::NaiveDateTime::parse_from_str(t, &fmt) {
+                    Ok(dt) => dt,
+                    Err(_) => chrono::NaiveDate::parse_from_str(t, &fmt)
+                        .context(format!("Could not parse time {t:?} with format {fmt:?}"))?
+                        .and_hms_opt(0, 0, 0)
+                        .expect("midnight is a valid time"),
+                };
+                Ok(dt.and_utc().timestamp_millis())
+            })
+            .collect()
+    }
+
+    /// Values of a Floats timeseries with the missing ones as None
+    fn ts_floats(
+        ts: &timeseries::TimeSeries,
+        nodata: Option<f64>,
+    ) -> anyhow::Result<Vec<Option<f64>>> {
+        ts.series()
+            .clone()
+            .to_attributes()
+            .into_iter()
+            .map(|a| match a {
+                Attribute::Float(f) if f.is_nan() || Some(f) == nodata => Ok(None),
+                Attribute::Float(f) => Ok(Some(f)),
+                a => bail!("Only Floats timeseries can be filled, found value {a}"),
+            })
+            .collect()
+    }
+
+    /// Fill the gaps in the value column with the methods of the chain in order
+    ///
+    /// Each method only fills the gaps up to its maximum length,
+    /// leaving the rest for the next ones; durations are converted to
+    /// timesteps with the step (in milliseconds) given with the `time`
+    /// column. The `add_neighbours` function should add the series of
+    /// the given nodes as the neighbour columns for the methods that
+    /// need them; in a chain, the methods without neighbours are
+    /// skipped. The regression and climatology methods are fitted on
+    /// the observed values only, not on the ones filled by the
+    /// methods before them. Returns the filled dataframe, and the
+    /// index of the method that filled each value.
+    fn fill_chain(
+        node: &mut NodeInner,
+        name: &str,
+        chain: &FillChain,
+        mut df: DataFrame,
+        value: &str,
+        time: Option<(&str, i64)>,
+        mut add_neighbours: impl FnMut(&[Node], DataFrame) -> anyhow::Result<DataFrame>,
+    ) -> anyhow::Result<(DataFrame, Vec<Option<usize>>)> {
+        let columns: Vec<Expr> = df.get_column_names().into_iter().map(col).collect();
+        let observed = df.column(value)?.clone();
+        let mut filled_by: Vec<Option<usize>> = vec![None; df.height()];
+        for (k, (method, limit)) in chain.steps.iter().enumerate() {
+            let nodes = match neighbours(node, method) {
+                Ok(nodes) => nodes,
+                // leave the gaps for the next methods
+                Err(e) if chain.steps.len() > 1 => {
+                    eprintln!("WARN {e}, skipping {}", method.label());
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let mut data = if nodes.is_empty() {
+                df
+            } else {
+                add_neighbours(&nodes, df)?
+            };
+            let mut source = data.clone();
+            if matches!(
+                method,
+                DataFillMethod::Regression(_) | DataFillMethod::Climatology(_)
+            ) {
+                source.with_column(observed.clone())?;
+            }
+            let fill = fill_expr(
+                node,
+                name,
+                method,
+                &source,
+                value,
+                time.map(|t| t.0),
+                &nodes,
+            )?;
+            let fill = source.lazy().select([fill.alias("_fill")]).collect()?;
+            data.with_column(fill.column("_fill")?.clone())?;
+            let mut gaps = col(value).is_null();
+            if let Some(limit) = limit {
+                let steps = limit.steps(time.map(|t| t.1)).map_err(anyhow::Error::msg)?;
+                gaps = gaps.and(gap_length(col(value)).lt_eq(lit(steps as i64)));
+            }
+            let before = data.column(value)?.is_null();
+            df = data
+                .lazy()
+                .with_column(
+                    when(gaps)
+                        .then(col("_fill"))
+                        .otherwise(col(value))
+                        .alias(value),
+                )
+                .select(columns.clone())
+                .collect()?;
+            let after = df.column(value)?.is_null();
+            for (i, (b, a)) in before
+                .into_no_null_iter()
+                .zip(after.into_no_null_iter())
+                .enumerate()
+            {
+                if b && !a {
+                    filled_by[i] = Some(k);
+                }
+            }
+        }
+        Ok((df, filled_by))
+    }
+
+    /// Expression to fill the nulls in the value column with the method
+    ///
+    /// The series of the `nodes` needed by the method should be in the
+    /// dataframe as the neighbour columns. The input/output ratio
+    /// methods fill the gaps with their sum scaled by the ratio of the
+    /// attribute (e.g. drainage area) of this node to theirs. The
+    /// regression method fills them from the neighbour with the best
+    /// fit, and saves the fit as `{name}_regression_*` attributes. The
+    /// climatology method needs the `time` column for the seasons.
+    fn fill_expr(
+        node: &mut NodeInner,
+        name: &str,
+        method: &DataFillMethod,
+        df: &DataFrame,
+        value: &str,
+        time: Option<&str>,
+        nodes: &[Node],
+    ) -> anyhow::Result<Expr> {
+        let val = col(value);
+        Ok(match method {
+            DataFillMethod::Climatology(clim) => {
+                let time = time.context("Climatology method needs the dates of the values")?;
+                clim.expr(val.cast(DataType::Float64), col(time))
+            }
+            DataFillMethod::InputRatio(var) | DataFillMethod::OutputRatio(var) => {
+                ratio_expr(val, nodes.len(), attr_ratio(node, nodes, var)?)
+            }
+            DataFillMethod::Regression(_) => {
+                let y = float_column(df, value)?;
+                let mut best: Option<(usize, Regression)> = None;
+                for i in 0..nodes.len() {
+                    let x = float_column(df, &neighbour_column(i))?;
+                    let fits = [Regression::linear(&y, &x), Regression::log_linear(&y, &x)];
+                    for fit in fits.into_iter().flatten() {
+                        if best.as_ref().map(|(_, b)| fit.r2 > b.r2).unwrap_or(true) {
+                            best = Some((i, fit));
+                        }
+                    }
+                }
+                let (i, fit) = best.context(format!(
+                    "Node {}: not enough values overlapping with the neighbours for regression",
+                    node.name()
+                ))?;
+                let donor = nodes[i].lock().name().to_string();
+                node.set_attr(
+                    &format!("{name}_regression_donor"),
+                    Attribute::String(donor.into()),
+                );
+                for (k, v) in fit.attrs() {
+                    node.set_attr(&format!("{name}_regression_{k}"), v);
+                }
+                when(val.clone().is_null())
+                    .then(fit.expr(col(&neighbour_column(i))))
+                    .otherwise(val)
+            }
+            m => {
+                let fill: ExprFunc = m.polars_fn()?;
+                fill(val)
+            }
+        })
+    }
+
+    /// Timestamps of the date column in milliseconds
+    fn timestamps(df: &LazyFrame, dtcol: &str) -> anyhow::Result<Vec<i64>> {
+        let times = df
+            .clone()
+            .select([col(dtcol)
+                .dt()
+                .timestamp(TimeUnit::Milliseconds)
+                .alias("timestamp")])
+            .collect()?;
+        Ok(times
+            .column("timestamp")?
+            .i64()?
+            .into_no_null_iter()
+            .collect())
+    }
+
+    /// Dates at every `step` from the first to the last of the sorted timestamps
+    fn calendar(dtcol: &str, times: &[i64], step: i64) -> anyhow::Result<LazyFrame> {
+        let (Some(start), Some(end)) = (times.first(), times.last()) else {
+            bail!("No dates found");
+        };
+        let calendar: Vec<i64> = (*start..=*end).step_by(step as usize).collect();
+        Ok(DataFrame::new(vec![Series::new(dtcol.into(), calendar)])?
+            .lazy()
+            .with_column(col(dtcol).cast(DataType::Datetime(TimeUnit::Milliseconds, None))))
+    }
+
+    /// Most common interval between the consecutive (sorted) timestamps
+    fn infer_step(times: &[i64]) -> Option<i64> {
+        let mut counts: HashMap<i64, usize> = HashMap::new();
+        for w in times.windows(2) {
+            *counts.entry(w[1] - w[0]).or_default() += 1;
+        }
+        counts
+            .into_iter()
+            .max_by_key(|(d, c)| (*c, -d))
+            .map(|(d, _)| d)
+            .filter(|d| *d > 0)
+    }
+
+    /// Values of the column as floats
+    fn float_column(df: &DataFrame, name: &str) -> anyhow::Result<Vec<Option<f64>>> {
+        Ok(df
+            .column(name)?
+            .cast(&DataType::Float64)?
+            .f64()?
+            .into_iter()
+            .collect())
+    }
+
+    /// Join the series of the neighbouring nodes read from their CSV files
+    fn join_neighbours(
+        mut df: LazyFrame,
+        nodes: &[Node],
+        file: &Template,
+        (dtcol, valcol): (&str, &str),
+    ) -> anyhow::Result<LazyFrame> {
+        for (i, n) in nodes.iter().enumerate() {
+            let n = n.lock();
+            let ndf = LazyCsvReader::new(n.render(file)?)
+                .with_has_header(true)
+                .with_try_parse_dates(true)
+                .finish()
+                .context(format!("Reading the CSV file for node {}", n.name()))?
+                .with_column(col(dtcol).cast(DataType::Datetime(TimeUnit::Milliseconds, None)))
+                .select([
+                    col(dtcol),
+                    col(valcol)
+                        .cast(DataType::Float64)
+                        .alias(&neighbour_column(i)),
+                ])
+                .unique_stable(Some(vec![dtcol.to_string()]), UniqueKeepStrategy::First);
+            df = df.join(
+                ndf,
+                [col(dtcol)],
+                [col(dtcol)],
+                JoinArgs::new(JoinType::Left),
+            );
+        }
+        Ok(df)
+    }
+
+    /// Name of the column with the series of the i-th neighbour
+    fn neighbour_column(i: usize) -> String {
+        format!("_neighbour_{i}")
+    }
+
+    /// Fill the nulls with the sum of the neighbour columns scaled by the ratio
+    fn ratio_expr(value: Expr, neighbours: usize, ratio: f64) -> Expr {
+        let total = (0..neighbours).fold(lit(0.0), |t, i| t + col(&neighbour_column(i)));
+        when(value.clone().is_null())
+            .then(total.mul(lit(ratio)))
+            .otherwise(value)
+    }
+
+    /// Neighbouring nodes whose series are needed by the method
+    ///
+    /// Regression uses the input and output nodes unless the node
+    /// names are given.
+    fn neighbours(node: &NodeInner, method: &DataFillMethod) -> anyhow::Result<Vec<Node>> {
+        let nodes: Vec<Node> = match method {
+            DataFillMethod::InputRatio(_) => node.inputs().iter().cloned().collect(),
+            DataFillMethod::OutputRatio(_) => {
+                node.output().into_option().cloned().into_iter().collect()
+            }
+            DataFillMethod::Regression(names) if names.is_empty() => node
+                .inputs()
+                .iter()
+                .chain(node.output().into_option())
+                .cloned()
+                .collect(),
+            DataFillMethod::Regression(names) => find_nodes(node, names)?,
+            _ => return Ok(vec![]),
+        };
+        if nodes.is_empty() {
+            bail!(
+                "Node {} has no neighbouring nodes for {method:?}",
+                node.name()
+            );
+        }
+        Ok(nodes)
+    }
+
+    /// Find the nodes with the given names in the network of the node
+    fn find_nodes(node: &NodeInner, names: &[String]) -> anyhow::Result<Vec<Node>> {
+        let mut found: HashMap<String, Node> = HashMap::new();
+        let mut visited: HashSet<String> = HashSet::new();
+        visited.insert(node.name().to_string());
+        let mut queue: Vec<Node> = node
+            .inputs()
+            .iter()
+            .chain(node.output().into_option())
+            .cloned()
+            .collect();
+        while let Some(n) = queue.pop() {
+            // the node being filled is already locked
+            let Some(inner) = n.try_lock() else {
+                continue;
+            };
+            let nm = inner.name().to_string();
+            if !visited.insert(nm.clone()) {
+                continue;
+            }
+            queue.extend(
+                inner
+                    .inputs()
+                    .iter()
+                    .chain(inner.output().into_option())
+                    .cloned(),
+            );
+            drop(inner);
+            if names.contains(&nm) {
+                found.insert(nm, n);
+            }
+        }
+        names
+            .iter()
+            .map(|nm| {
+                found
+                    .remove(nm)
+                    .context(format!("Node {nm} not found in the network"))
+            })
+            .collect()
+    }
+
+    /// Ratio of the attribute of the node to the sum of the
+    /// attribute of the neighbouring nodes
+    fn attr_ratio(node: &NodeInner, nodes: &[Node], var: &str) -> anyhow::Result<f64> {
+        let val: f64 = node
+            .try_attr(var)
+            .map_err(|e| anyhow::Error::msg(format!("Node {}: {e}", node.name())))?;
+        let total = nodes
+            .iter()
+            .map(|n| {
+                let n = n.lock();
+                n.try_attr::<f64>(var)
+                    .map_err(|e| anyhow::Error::msg(format!("Node {}: {e}", n.name())))
+            })
+            .sum::<anyhow::Result<f64>>()?;
+        if total == 0.0 {
+            bail!(
+                "Node {}: sum of {var} of the neighbours is zero",
+                node.name()
+            );
+        }
+        Ok(val / total)
+    }
+
     /// Write the given nodes to csv with given attributes and experiment results
     #[network_func]
     fn save_experiments_csv(
@@ -104,6 +754,8 @@ mod datafill {
         /// list of errors to write
         errors: Vec<String>,
         filter: Option<Vec<bool>>,
+        /// list of methods used in the experiments
+        methods: Option<Vec<String>>,
     ) -> anyhow::Result<()> {
         let f = File::create(&outfile)?;
         let mut w = BufWriter::new(f);
@@ -116,7 +768,8 @@ mod datafill {
             if middle { "," } else { "" },
             errors.join(",")
         )?;
-        let methods = ["forward", "backward", "linear", "iratio", "oratio"];
+        let methods: Vec<String> =
+            methods.unwrap_or_else(|| EXPERIMENT_METHODS.iter().map(|m| m.to_string()).collect());
         let nodes: Vec<&Node> = if let Some(filt) = filter {
             net.nodes()
                 .zip(filt)
@@ -133,7 +786,7 @@ mod datafill {
                 .map(|a| node.attr(a).map(|a| a.to_string()).unwrap_or_default())
                 .collect();
 
-            for m in methods {
+            for m in methods.iter().map(|m| method_label(m)) {
                 let series: Vec<Vec<String>> = errors
                     .iter()
                     .map(|e| {
@@ -173,7 +826,25 @@ mod datafill {
         Ok(())
     }
 
-    #[node_func(experiments = 10usize, samples = 100usize)]
+    /// Compare the data fill methods by removing known values and filling them
+    ///
+    /// Each experiment removes `samples` random values from the rows
+    /// where the node and its neighbours have data, fills them with
+    /// each of the `methods`, and saves the `errors` between the
+    /// filled and the known values as `{name}_{method}_{error}`
+    /// series. Give a `seed` to repeat the same experiments, and
+    /// `outdir` to save the filled values of each experiment as
+    /// `{outdir}/{experiment}-{method}.csv`. The methods are named
+    /// without their options (e.g. `iratio` for `iratio:area`), so
+    /// only one variant of each method can be compared at a time.
+    ///
+    /// With `gaps="observed"`, contiguous blocks of values are removed
+    /// instead of single values, with their lengths drawn from the
+    /// lengths of the gaps in the node's own data, until at least
+    /// `samples` values are removed. The blocks are only taken from
+    /// the consecutive dates where all the values are known, so they
+    /// don't extend over the real gaps.
+    #[node_func(experiments = 10usize, samples = 100usize, gaps = "random")]
     fn datafill_experiment(
         node: &mut NodeInner,
         /// Prefix for name of the series to save metrics on
@@ -189,7 +860,69 @@ mod datafill {
         experiments: usize,
         /// Number of samples on each experiment
         samples: usize,
+        /// Seed for the random samples, random by default
+        seed: Option<u64>,
+        /// Methods to compare, forward/backward/linear/iratio/oratio by default
+        methods: Option<Vec<String>>,
+        /// Errors to calculate (rmse/nrmse/abserr/nse), all by default
+        errors: Option<Vec<String>>,
+        /// Template of the directory to save the filled values in
+        outdir: Option<Template>,
+        /// How to remove the values: random/observed
+        gaps: String,
     ) -> anyhow::Result<()> {
+        let methods: Vec<(String, DataFillMethod)> = methods
+            .unwrap_or_else(|| EXPERIMENT_METHODS.iter().map(|m| m.to_string()).collect())
+            .into_iter()
+            .map(|m| {
+                // ratio methods use the ratio_var if not given
+                let spec = match m.as_str() {
+                    "iratio" | "oratio" => format!("{m}:{ratio_var}"),
+                    _ => m.clone(),
+                };
+                let method = DataFillMethod::try_from_attr(&Attribute::String(spec.into()))
+                    .map_err(anyhow::Error::msg)?;
+                Ok((method_label(&m).to_string(), method))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let mut labels: HashSet<&str> = HashSet::new();
+        if let Some((m, _)) = methods.iter().find(|(m, _)| !labels.insert(m)) {
+            bail!(
+                "Multiple methods save their errors as {name}_{m}_*, \
+                 run the experiments with different names to compare them"
+            );
+        }
+        let err_metrics: Vec<String> = match errors {
+            Some(errs) => {
+                if let Some(e) = errs.iter().find(|e| !ERROR_METRICS.contains(&e.as_str())) {
+                    bail!("Unknown error {e:?}, use rmse/nrmse/abserr/nse");
+                }
+                errs
+            }
+            None => ERROR_METRICS.iter().map(|e| e.to_string()).collect(),
+        };
+        let outdir = match outdir {
+            Some(templ) => {
+                let dir = PathBuf::from(node.render(&templ)?);
+                std::fs::create_dir_all(&dir).context(format!("Creating directory {dir:?}"))?;
+                Some(dir)
+            }
+            None => None,
+        };
+        let mut method_nodes: Vec<Vec<Node>> = Vec::with_capacity(methods.len());
+        let mut usable = Vec::with_capacity(methods.len());
+        for (mname, method) in methods {
+            match neighbours(node, &method) {
+                Ok(nodes) => {
+                    method_nodes.push(nodes);
+                    usable.push((mname, method));
+                }
+                Err(e) => eprintln!("WARN {e}, skipping {mname}"),
+            }
+        }
+        let methods = usable;
+        let mut joined: HashSet<String> = HashSet::new();
+
         let csv = node.render(&file)?;
         let (dtcol, valcol) = match &columns {
             Some((dt, val)) => (col(dt), col(val)),
@@ -212,11 +945,14 @@ mod datafill {
                 valcol.clone().alias(node.name()),
             ])
             .select([col(dtname), col(node.name())]);
-        node.inputs()
+        method_nodes
             .iter()
-            .chain(node.output().into_option())
+            .flatten()
             .try_for_each(|n| -> anyhow::Result<()> {
                 let n = n.lock();
+                if !joined.insert(n.name().to_string()) {
+                    return Ok(());
+                }
                 let csv = n.render(&file)?;
                 let idf = LazyCsvReader::new(csv)
                     .with_has_header(columns.is_some())
@@ -239,8 +975,60 @@ mod datafill {
                 );
                 Ok(())
             })?;
-        let df2 = df.drop_nulls(None).collect()?;
+        // reindexed onto the calendar, so the missing dates are gaps too
+        let df = df
+            .with_column(col(dtname).cast(DataType::Datetime(TimeUnit::Milliseconds, None)))
+            .filter(col(dtname).is_not_null())
+            .sort([dtname], Default::default())
+            .unique_stable(Some(vec![dtname.to_string()]), UniqueKeepStrategy::First);
+        let times = timestamps(&df, dtname)?;
+        let step = infer_step(&times).context(format!(
+            "Node {}: cannot infer the frequency of the dates",
+            node.name()
+        ))?;
+        let full = calendar(dtname, &times, step)?
+            .join(
+                df,
+                [col(dtname)],
+                [col(dtname)],
+                JoinArgs::new(JoinType::Left),
+            )
+            .collect()?;
+        // rows where the node and all its neighbours have values
+        let mut complete = vec![true; full.height()];
+        for c in full.get_columns() {
+            for (ok, null) in complete.iter_mut().zip(c.is_null().into_no_null_iter()) {
+                *ok &= !null;
+            }
+        }
+        let observed = match gaps.as_str() {
+            "random" => None,
+            "observed" => {
+                let missing = full.column(node.name())?.is_null();
+                let lengths = gap_lengths(missing.into_no_null_iter());
+                if lengths.is_empty() {
+                    eprintln!(
+                        "WARN Node {} has no gaps, removing random values instead",
+                        node.name()
+                    );
+                    None
+                } else {
+                    Some(lengths)
+                }
+            }
+            g => bail!("Unknown gaps {g:?}, use random/observed"),
+        };
+        let df2 = full.lazy().drop_nulls(None).collect()?;
         let ht = df2.height();
+        // index of the rows of the full frame in the complete rows
+        let rows: Vec<i64> = complete
+            .iter()
+            .scan(0, |n, c| {
+                let i = *n;
+                *n += *c as i64;
+                Some(i)
+            })
+            .collect();
         if ht < (samples / 10) {
             println!(
                 "Warn: Node {} doesn't have enough values ({ht}) to experiment, skipping",
@@ -248,37 +1036,31 @@ mod datafill {
             );
             return Ok(());
         }
-        let err_metrics = ["rmse", "nrmse", "abserr", "nse"];
-        let mut errors: HashMap<(&'static str, &'static str), Vec<f64>> = HashMap::new();
-        // ["rmse", "nrmse", "abserr", "nse"]
-        //     .into_iter()
-        //     .map(|k| (k, Vec::with_capacity(experiments)))
-        //     .collect();
-        let fill_methods = [
-            (
-                "forward",
-                DataFillMethod::Strategy(FillNullStrategy::Forward(None)),
-            ),
-            (
-                "backward",
-                DataFillMethod::Strategy(FillNullStrategy::Backward(None)),
-            ),
-            ("linear", DataFillMethod::Linear),
-        ];
+        let samples = samples.min(ht);
+        let mut errors: HashMap<(String, String), Vec<f64>> = HashMap::new();
+        let mut rng = match seed {
+            Some(s) => StdRng::seed_from_u64(s),
+            None => StdRng::from_rng(&mut rand::rng()),
+        };
         for i in 0..experiments {
-            let mut rng = StdRng::from_rng(&mut rand::rng());
-            let indices: Vec<i64> = rand::seq::index::sample(&mut rng, ht, samples)
-                .iter()
-                .map(|i| i as i64)
-                .collect();
+            let indices: Vec<i64> = match &observed {
+                Some(lengths) => sample_gaps(&mut rng, &complete, samples, lengths)
+                    .into_iter()
+                    .map(|i| rows[i])
+                    .collect(),
+                None => rand::seq::index::sample(&mut rng, ht, samples)
+                    .iter()
+                    .map(|i| i as i64)
+                    .collect(),
+            };
             let vals = Series::new("nulls".into(), indices);
 
             let df = df2
                 .clone()
                 .lazy()
                 .with_column(
-                    col(dtname)
-                        .cum_count(false)
+                    // cum_count starts at 1, while the indices start at 0
+                    (col(dtname).cum_count(false) - lit(1))
                         .is_in(lit(vals))
                         .alias("sample"),
                 )
@@ -289,94 +1071,38 @@ mod datafill {
                         .alias("new_vals"),
                 )
                 .collect()?;
-            for (mname, method) in &fill_methods {
-                let fill: ExprFunc = method.polars_fn()?;
+            for ((mname, method), nodes) in methods.iter().zip(&method_nodes) {
+                let neighbours: Vec<Expr> = nodes
+                    .iter()
+                    .enumerate()
+                    .map(|(j, n)| col(n.lock().name()).alias(&neighbour_column(j)))
+                    .collect();
+                let df = df.clone().lazy().with_columns(neighbours).collect()?;
+                let fill = fill_expr(node, &name, method, &df, "new_vals", Some(dtname), nodes)?;
                 let mut df = df
-                    .clone()
                     .lazy()
-                    .with_column(fill(col("new_vals")))
+                    .with_column(fill.alias("new_vals"))
                     .filter(col("sample"))
                     .collect()?;
-                let mut file =
-                    std::fs::File::create(format!("/tmp/experiments/{i}-{mname}.csv")).unwrap();
-                CsvWriter::new(&mut file).finish(&mut df).unwrap();
+                if let Some(dir) = &outdir {
+                    let path = dir.join(format!("{i}-{mname}.csv"));
+                    let mut file = File::create(&path).context(format!("Creating {path:?}"))?;
+                    CsvWriter::new(&mut file).finish(&mut df)?;
+                }
                 let obs: Vec<f64> = df.column(node.name())?.f64()?.into_no_null_iter().collect();
-                let sim: Vec<f64> = df.column("new_vals")?.f64()?.into_no_null_iter().collect();
+                let sim: Vec<f64> = df
+                    .column("new_vals")?
+                    .f64()?
+                    .into_iter()
+                    .map(|v| v.unwrap_or(f64::NAN))
+                    .collect();
                 for e in &err_metrics {
                     let errs = errors
-                        .entry((mname, e))
+                        .entry((mname.clone(), e.clone()))
                         .or_insert_with(|| Vec::with_capacity(experiments));
-                    errs.push(calc_error(&obs, &sim, e).expect("should be a known error"));
+                    errs.push(calc_error(&obs, &sim, e).map_err(anyhow::Error::msg)?);
                 }
             }
-            // input ratio
-            let var = &ratio_var;
-            let val: f64 = node.try_attr(var).unwrap_or(0.0);
-            let oval: f64 = node
-                .output()
-                .map(|o| o.lock().try_attr(var).unwrap_or(0.0))
-                .unwrap_or(0.0);
-            let ival: f64 = node
-                .inputs()
-                .iter()
-                .map(|n| n.lock().try_attr::<f64>(var).unwrap_or(0.0))
-                .sum();
-            let iratio = val / ival;
-            let oratio = val / oval;
-            let isum: Vec<Expr> = node.inputs().iter().map(|n| col(n.lock().name())).collect();
-            let ifill = |mut e: Expr| -> Expr {
-                for i in isum {
-                    e = e + i;
-                }
-                e.mul(lit(iratio))
-            };
-            let oname = node
-                .output()
-                .map(|o| o.lock().name().to_string())
-                .unwrap_or_default();
-            let mut df = df
-                .clone()
-                .lazy()
-                .with_column(ifill(lit(0)).alias("iratio_vals"))
-                .with_column(col(&oname).mul(lit(oratio)).alias("oratio_vals"))
-                .with_column(
-                    when(col("new_vals").is_null())
-                        .then(col("iratio_vals"))
-                        .otherwise(col("new_vals"))
-                        .alias("iratio_fills"),
-                )
-                .with_column(
-                    when(col("new_vals").is_null())
-                        .then(col("oratio_vals"))
-                        .otherwise(col("new_vals"))
-                        .alias("oratio_fills"),
-                )
-                .filter(col("sample"))
-                .collect()?;
-            let mut file =
-                std::fs::File::create(format!("/tmp/experiments/{i}-ratio.csv")).unwrap();
-            CsvWriter::new(&mut file).finish(&mut df).unwrap();
-            let obs: Vec<f64> = df.column(node.name())?.f64()?.into_no_null_iter().collect();
-            let sim1: Vec<f64> = df
-                .column("iratio_fills")?
-                .f64()?
-                .into_no_null_iter()
-                .collect();
-            let sim2: Vec<f64> = df
-                .column("oratio_fills")?
-                .f64()?
-                .into_no_null_iter()
-                .collect();
-            for e in err_metrics {
-                let errs = errors
-                    .entry(("iratio", e))
-                    .or_insert_with(|| Vec::with_capacity(experiments));
-                errs.push(calc_error(&obs, &sim1, e).expect("should be a known error"));
-                let errs = errors
-                    .entry(("oratio", e))
-                    .or_insert_with(|| Vec::with_capacity(experiments));
-                errs.push(calc_error(&obs, &sim2, e).expect("should be a known error"));
-            }
         }
         for ((mname, e), errs) in errors {
             node.set_series(&format!("{name}_{mname}_{e}"), errs.into());
@@ -384,6 +1110,11 @@ mod datafill {
         Ok(())
     }
 
+    /// Name of the method used in the names of the experiment series
+    fn method_label(method: &str) -> &str {
+        method.split(':').next().unwrap_or(method)
+    }
+
     fn calc_error(obs: &[f64], sim: &[f64], error: &str) -> Result<f64, String> {
         let err = match error {
             "rmse" => calc_rmse(obs, sim),
@@ -453,9 +1184,16 @@ mod utils {
     use nadi_core::anyhow;
     use nadi_core::prelude::*;
     use polars::prelude::*;
+    use rand::Rng;
 
     pub type ExprFunc = fn(Expr) -> Expr;
 
+    /// Methods compared by default in the data fill experiments
+    pub const EXPERIMENT_METHODS: [&str; 5] = ["forward", "backward", "linear", "iratio", "oratio"];
+
+    /// Error metrics that can be calculated in the data fill experiments
+    pub const ERROR_METRICS: [&str; 4] = ["rmse", "nrmse", "abserr", "nse"];
+
     #[derive(Debug)]
     pub enum DataFillMethod {
         Strategy(FillNullStrategy),
@@ -463,9 +1201,220 @@ mod utils {
         Nearest,
         InputRatio(String),
         OutputRatio(String),
+        Regression(Vec<String>),
+        Climatology(Climatology),
+    }
+
+    /// Longest gap a method is allowed to fill
+    #[derive(Debug, Clone, Copy)]
+    pub enum MaxGap {
+        Steps(usize),
+        /// Duration in milliseconds
+        Millis(i64),
+    }
+
+    impl MaxGap {
+        /// Number of timesteps (e.g. `5`) or a duration (e.g. `2d`)
+        pub fn parse(limit: &str) -> Result<Self, String> {
+            let limit = limit.trim();
+            if !limit.is_empty() && limit.chars().all(|c| c.is_ascii_digit()) {
+                limit
+                    .parse()
+                    .map(Self::Steps)
+                    .map_err(|e| format!("Invalid max gap {limit:?}: {e}"))
+            } else {
+                parse_frequency(limit).map(Self::Millis)
+            }
+        }
+
+        /// Number of timesteps, with the length of a timestep in milliseconds
+        pub fn steps(&self, step: Option<i64>) -> Result<usize, String> {
+            match (self, step) {
+                (Self::Steps(n), _) => Ok(*n),
+                (Self::Millis(ms), Some(step)) => Ok((ms / step) as usize),
+                (Self::Millis(_), None) => {
+                    Err("Max gap as duration needs the dates, give it in timesteps".to_string())
+                }
+            }
+        }
+    }
+
+    /// Methods to fill the gaps with in order, with the longest gaps
+    /// they are allowed to fill
+    #[derive(Debug)]
+    pub struct FillChain {
+        pub steps: Vec<(DataFillMethod, Option<MaxGap>)>,
+    }
+
+    impl From<DataFillMethod> for FillChain {
+        fn from(method: DataFillMethod) -> Self {
+            Self {
+                steps: vec![(method, None)],
+            }
+        }
+    }
+
+    impl FillChain {
+        /// Use the limit for the methods without their own
+        pub fn with_max_gap(mut self, max_gap: Option<MaxGap>) -> Self {
+            for (_, limit) in self.steps.iter_mut() {
+                if limit.is_none() {
+                    *limit = max_gap;
+                }
+            }
+            self
+        }
+
+        pub fn check_dtype(&self, dtype: &str) -> Result<(), String> {
+            self.steps
+                .iter()
+                .try_for_each(|(m, _)| m.check_dtype(dtype))
+        }
+
+        /// Whether any of the methods or limits need the dates of the values
+        pub fn needs_dates(&self) -> bool {
+            self.steps.iter().any(|(m, l)| {
+                matches!(m, DataFillMethod::Climatology(_)) || matches!(l, Some(MaxGap::Millis(_)))
+            })
+        }
+
+        pub fn labels(&self) -> Vec<&'static str> {
+            self.steps.iter().map(|(m, _)| m.label()).collect()
+        }
+    }
+
+    impl FromAttribute for FillChain {
+        fn from_attr(value: &Attribute) -> Option<Self> {
+            FromAttribute::try_from_attr(value).ok()
+        }
+        fn try_from_attr(value: &Attribute) -> Result<Self, String> {
+            let strval = String::try_from_attr(value)?;
+            let steps = strval
+                .split("->")
+                .map(|step| {
+                    let step = step.trim();
+                    let (method, limit) =
+                        match step.strip_suffix(')').and_then(|s| s.split_once('(')) {
+                            Some((m, lim)) => {
+                                let lim = lim.trim().strip_prefix("max").ok_or_else(|| {
+                                    format!("Invalid limit {lim:?} for {m:?}, use (max N)")
+                                })?;
+                                (m.trim(), Some(MaxGap::parse(lim)?))
+                            }
+                            None => (step, None),
+                        };
+                    let method = DataFillMethod::try_from_attr(&Attribute::String(method.into()))?;
+                    Ok((method, limit))
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            Ok(Self { steps })
+        }
+    }
+
+    /// Fill from the long term statistics of the same season
+    #[derive(Debug, Clone)]
+    pub struct Climatology {
+        /// Use the median instead of the mean
+        pub median: bool,
+        /// Season to group the values by: doy/month/hour
+        pub period: String,
+        /// Gaps up to this length are filled with linear interpolation
+        pub linear: usize,
+    }
+
+    impl Climatology {
+        pub fn expr(&self, value: Expr, time: Expr) -> Expr {
+            let season = match self.period.as_str() {
+                "month" => time.dt().month(),
+                "hour" => time.dt().hour(),
+                _ => time.dt().ordinal_day(),
+            };
+            let stat = if self.median {
+                value.clone().median()
+            } else {
+                value.clone().mean()
+            };
+            let filled = when(value.clone().is_null())
+                .then(stat.over([season]))
+                .otherwise(value.clone());
+            if self.linear == 0 {
+                return filled;
+            }
+            // gaps at the ends can't be interpolated
+            let interpolated = value
+                .clone()
+                .interpolate(InterpolationMethod::Linear)
+                .fill_null(filled.clone());
+            when(
+                value
+                    .clone()
+                    .is_null()
+                    .and(gap_length(value).lt_eq(lit(self.linear as i64))),
+            )
+            .then(interpolated)
+            .otherwise(filled)
+        }
+    }
+
+    /// Length of the gap each null value is in
+    pub fn gap_length(value: Expr) -> Expr {
+        // the nulls are grouped with the last value before them
+        value
+            .clone()
+            .is_null()
+            .cast(DataType::Int64)
+            .sum()
+            .over([value.is_not_null().cum_sum(false)])
     }
 
     impl DataFillMethod {
+        /// Name of the method used in the fill flags
+        pub fn label(&self) -> &'static str {
+            match self {
+                Self::Strategy(FillNullStrategy::Forward(_)) => "forward",
+                Self::Strategy(FillNullStrategy::Backward(_)) => "backward",
+                Self::Strategy(FillNullStrategy::Mean) => "mean",
+                Self::Strategy(FillNullStrategy::Min) => "min",
+                Self::Strategy(FillNullStrategy::Max) => "max",
+                Self::Strategy(FillNullStrategy::Zero) => "zero",
+                Self::Strategy(FillNullStrategy::One) => "one",
+                Self::Strategy(FillNullStrategy::MaxBound) => "maxbound",
+                Self::Strategy(FillNullStrategy::MinBound) => "minbound",
+                Self::Strategy(_) => "strategy",
+                Self::Linear => "linear",
+                Self::Nearest => "nearest",
+                Self::InputRatio(_) => "iratio",
+                Self::OutputRatio(_) => "oratio",
+                Self::Regression(_) => "regression",
+                Self::Climatology(_) => "climatology",
+            }
+        }
+
+        /// Check if the method can fill the values of the data type
+        pub fn check_dtype(&self, dtype: &str) -> Result<(), String> {
+            let ffill_bfill = matches!(
+                self,
+                Self::Strategy(FillNullStrategy::Forward(_) | FillNullStrategy::Backward(_))
+            );
+            let ok = match dtype {
+                "Floats" | "Integers" => true,
+                "Strings" | "Booleans" => ffill_bfill,
+                "Dates" | "DateTimes" => ffill_bfill || matches!(self, Self::Nearest),
+                _ => {
+                    return Err(format!(
+                        "{dtype} is not supported or is not a recognized data type"
+                    ))
+                }
+            };
+            if ok {
+                Ok(())
+            } else {
+                Err(format!(
+                    "Data fill method {self:?} cannot be used for {dtype}"
+                ))
+            }
+        }
+
         pub fn polars_fn(&self) -> anyhow::Result<ExprFunc> {
             Ok(match self {
                 // All this because we cannot capture s in a fn
@@ -546,8 +1495,316 @@ mod utils {
                         Ok(Self::OutputRatio(data.to_string()))
                     }
                 }
+                "regression" => Ok(Self::Regression(
+                    data.split(',')
+                        .map(str::trim)
+                        .filter(|n| !n.is_empty())
+                        .map(String::from)
+                        .collect(),
+                )),
+                "climatology" => {
+                    let mut clim = Climatology {
+                        median: false,
+                        period: "doy".to_string(),
+                        linear: 0,
+                    };
+                    for opt in data.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+                        match opt {
+                            "mean" => clim.median = false,
+                            "median" => clim.median = true,
+                            "doy" | "month" | "hour" => clim.period = opt.to_string(),
+                            n => {
+                                clim.linear = n.parse().map_err(|_| {
+                                    format!(
+                                        "Unknown climatology option {n:?}, use mean/median, doy/month/hour or a gap length"
+                                    )
+                                })?
+                            }
+                        }
+                    }
+                    Ok(Self::Climatology(clim))
+                }
                 x => Err(format!("Data fill method {x:?} not recognized")),
             }
         }
     }
+
+    /// Lengths of the runs of missing values
+    pub fn gap_lengths(missing: impl Iterator<Item = bool>) -> Vec<usize> {
+        let mut lengths = Vec::new();
+        let mut run = 0;
+        for m in missing {
+            if m {
+                run += 1;
+            } else if run > 0 {
+                lengths.push(run);
+                run = 0;
+            }
+        }
+        if run > 0 {
+            lengths.push(run);
+        }
+        lengths
+    }
+
+    /// Indices of contiguous gaps with lengths drawn from the given ones
+    ///
+    /// Gaps are added until at least `samples` values are in them,
+    /// only over the `available` values; they don't touch each other,
+    /// the ends or the unavailable values, so there are always values
+    /// on both sides to fill them from.
+    pub fn sample_gaps(
+        rng: &mut impl Rng,
+        available: &[bool],
+        samples: usize,
+        lengths: &[usize],
+    ) -> Vec<usize> {
+        let len = available.len();
+        if len < 3 {
+            return vec![];
+        }
+        let mut masked = vec![false; len];
+        let mut count = 0;
+        let mut attempts = 0;
+        while count < samples && attempts < 100 * samples.max(1) {
+            attempts += 1;
+            let l = lengths[rng.random_range(0..lengths.len())].clamp(1, len / 2);
+            if len < l + 2 {
+                continue;
+            }
+            let start = rng.random_range(1..(len - l));
+            let block = (start - 1)..=(start + l);
+            if masked[block.clone()].iter().any(|m| *m) || !available[block].iter().all(|a| *a) {
+                continue;
+            }
+            masked[start..(start + l)].fill(true);
+            count += l;
+        }
+        masked
+            .into_iter()
+            .enumerate()
+            .filter(|(_, m)| *m)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Length in milliseconds of a frequency like `daily`, `1h` or `15min`
+    pub fn parse_frequency(freq: &str) -> Result<i64, String> {
+        let freq = match freq {
+            "daily" => "1d",
+            "hourly" => "1h",
+            "weekly" => "1w",
+            f => f,
+        };
+        let split = freq
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(freq.len());
+        let (num, unit) = freq.split_at(split);
+        let num: i64 = if num.is_empty() {
+            1
+        } else {
+            num.parse()
+                .map_err(|e| format!("Invalid frequency {freq:?}: {e}"))?
+        };
+        let unit: i64 = match unit.trim() {
+            "ms" => 1,
+            "s" | "sec" => 1_000,
+            "m" | "min" => 60_000,
+            "h" | "hour" => 3_600_000,
+            "d" | "day" => 86_400_000,
+            "w" | "week" => 7 * 86_400_000,
+            u => {
+                return Err(format!(
+                    "Unknown unit {u:?} in frequency {freq:?}, use ms/s/min/h/d/w"
+                ))
+            }
+        };
+        if num == 0 {
+            return Err(format!("Frequency {freq:?} should not be zero"));
+        }
+        Ok(num * unit)
+    }
+
+    /// Minimum number of overlapping values to fit a regression
+    const MIN_OVERLAP: usize = 10;
+
+    /// Least squares fit of a series on the series of a neighbour
+    #[derive(Debug, Clone)]
+    pub struct Regression {
+        /// Fit on the logarithm of the values
+        pub log: bool,
+        pub intercept: f64,
+        pub slope: f64,
+        /// Coefficient of determination of the predicted values, in
+        /// the original units for both models so they can be compared
+        pub r2: f64,
+        /// Number of overlapping values used for the fit
+        pub count: usize,
+    }
+
+    impl Regression {
+        /// Fit `y = a + b x` on the values present in both series
+        pub fn linear(y: &[Option<f64>], x: &[Option<f64>]) -> Option<Self> {
+            let pairs: Vec<(f64, f64)> = x
+                .iter()
+                .zip(y)
+                .filter_map(|(x, y)| Some(((*x)?, (*y)?)))
+                .collect();
+            Self::fit(&pairs, false)
+        }
+
+        /// Fit `ln y = a + b ln x` on the positive values present in both series
+        pub fn log_linear(y: &[Option<f64>], x: &[Option<f64>]) -> Option<Self> {
+            let pairs: Vec<(f64, f64)> = x
+                .iter()
+                .zip(y)
+                .filter_map(|(x, y)| Some(((*x)?, (*y)?)))
+                .filter(|(x, y)| *x > 0.0 && *y > 0.0)
+                .collect();
+            Self::fit(&pairs, true)
+        }
+
+        fn fit(pairs: &[(f64, f64)], log: bool) -> Option<Self> {
+            let count = pairs.len();
+            if count < MIN_OVERLAP {
+                return None;
+            }
+            let tr = |v: f64| if log { v.ln() } else { v };
+            let n = count as f64;
+            let mx = pairs.iter().map(|p| tr(p.0)).sum::<f64>() / n;
+            let my = pairs.iter().map(|p| tr(p.1)).sum::<f64>() / n;
+            let (mut sxx, mut syy, mut sxy) = (0.0, 0.0, 0.0);
+            for (x, y) in pairs {
+                let (x, y) = (tr(*x), tr(*y));
+                sxx += (x - mx) * (x - mx);
+                syy += (y - my) * (y - my);
+                sxy += (x - mx) * (y - my);
+            }
+            if sxx == 0.0 || syy == 0.0 {
+                return None;
+            }
+            let slope = sxy / sxx;
+            let intercept = my - slope * mx;
+            // R² of the back transformed predictions for the log model
+            let mean = pairs.iter().map(|p| p.1).sum::<f64>() / n;
+            let (mut ss_res, mut ss_tot) = (0.0, 0.0);
+            for (x, y) in pairs {
+                let pred = intercept + slope * tr(*x);
+                let pred = if log { pred.exp() } else { pred };
+                ss_res += (y - pred) * (y - pred);
+                ss_tot += (y - mean) * (y - mean);
+            }
+            Some(Self {
+                log,
+                intercept,
+                slope,
+                r2: 1.0 - ss_res / ss_tot,
+                count,
+            })
+        }
+
+        /// Values predicted from the series of the neighbour
+        pub fn expr(&self, x: Expr) -> Expr {
+            let (a, b) = (lit(self.intercept), lit(self.slope));
+            if self.log {
+                when(x.clone().gt(lit(0.0)))
+                    .then((a + b * x.log(std::f64::consts::E)).exp())
+                    .otherwise(lit(NULL))
+            } else {
+                a + b * x
+            }
+        }
+
+        /// Details of the fit to save as attributes
+        pub fn attrs(&self) -> Vec<(&'static str, Attribute)> {
+            vec![
+                (
+                    "model",
+                    Attribute::String(if self.log { "loglinear" } else { "linear" }.into()),
+                ),
+                ("intercept", Attribute::Float(self.intercept)),
+                ("slope", Attribute::Float(self.slope)),
+                ("r2", Attribute::Float(self.r2)),
+                ("count", Attribute::Integer(self.count as i64)),
+            ]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::utils::*;
+    use nadi_core::prelude::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn chain(s: &str) -> Result<FillChain, String> {
+        FillChain::try_from_attr(&Attribute::String(s.into()))
+    }
+
+    #[test]
+    fn fill_chain_limits() {
+        let ch = chain("linear(max 3) -> iratio:area(max 2d)").unwrap();
+        assert_eq!(ch.steps.len(), 2);
+        assert!(matches!(
+            ch.steps[0],
+            (DataFillMethod::Linear, Some(MaxGap::Steps(3)))
+        ));
+        assert!(matches!(
+            &ch.steps[1],
+            (DataFillMethod::InputRatio(v), Some(MaxGap::Millis(172_800_000))) if v == "area"
+        ));
+        let ch = chain("forward -> backward")
+            .unwrap()
+            .with_max_gap(Some(MaxGap::Steps(5)));
+        assert!(ch
+            .steps
+            .iter()
+            .all(|(_, l)| matches!(l, Some(MaxGap::Steps(5)))));
+    }
+
+    #[test]
+    fn fill_chain_invalid_limit() {
+        assert!(chain("linear(min 3)").is_err());
+        assert!(chain("linear(max)").is_err());
+    }
+
+    #[test]
+    fn gap_lengths_runs() {
+        let missing = [true, false, true, true, false, false, true];
+        assert_eq!(gap_lengths(missing.into_iter()), vec![1, 2, 1]);
+    }
+
+    #[test]
+    fn sample_gaps_available() {
+        let available: Vec<bool> = (0..200).map(|i| i % 37 != 0 && i % 11 != 5).collect();
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let gaps = sample_gaps(&mut rng, &available, 30, &[1, 2, 4]);
+            assert!(gaps.len() >= 30);
+            for i in gaps {
+                assert!(i > 0 && i < available.len() - 1);
+                assert!(available[i - 1] && available[i] && available[i + 1]);
+            }
+        }
+    }
+
+    #[test]
+    fn regression_fit() {
+        let x: Vec<Option<f64>> = (1..=20).map(|v| Some(v as f64)).collect();
+        let y: Vec<Option<f64>> = x.iter().map(|v| v.map(|v| 2.0 + 3.0 * v)).collect();
+        let fit = Regression::linear(&y, &x).unwrap();
+        assert!((fit.slope - 3.0).abs() < 1e-9);
+        assert!((fit.intercept - 2.0).abs() < 1e-9);
+        assert!((fit.r2 - 1.0).abs() < 1e-9);
+        assert_eq!(fit.count, 20);
+
+        let y: Vec<Option<f64>> = x
+            .iter()
+            .map(|v| v.map(|v| std::f64::consts::E * v.sqrt()))
+            .collect();
+        let fit = Regression::log_linear(&y, &x).unwrap();
+        assert!((fit.slope - 0.5).abs() < 1e-9);
+        assert!((fit.intercept - 1.0).abs() < 1e-9);
+        assert!(Regression::linear(&y[..5], &x[..5]).is_none());
+    }
 }
@@ -0,0 +1,209 @@
+use nadi_core::attrs::{Attribute, FromAttributeRelaxed};
+
+/// Rule to combine the values of multiple features into one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    First,
+    Sum,
+    Min,
+    Max,
+    Mean,
+    Count,
+    List,
+}
+
+impl Aggregate {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        Ok(match name {
+            "first" => Self::First,
+            "sum" => Self::Sum,
+            "min" => Self::Min,
+            "max" => Self::Max,
+            "mean" => Self::Mean,
+            "count" => Self::Count,
+            "list" => Self::List,
+            x => {
+                return Err(format!(
+                    "Aggregation {x:?} not recognized, use first/sum/min/max/mean/count/list"
+                ))
+            }
+        })
+    }
+
+    /// Combine the values, `None` if there are no values to combine
+    ///
+    /// The numeric rules take the first value when none of the
+    /// values are numbers, so the same rule can be applied to text
+    /// fields as well; except min and max, which compare the dates,
+    /// date times and strings among themselves.
+    pub fn apply(&self, values: Vec<Attribute>) -> Option<Attribute> {
+        if values.is_empty() {
+            return None;
+        }
+        match self {
+            Self::First => values.into_iter().next(),
+            Self::Min | Self::Max if !values.iter().any(is_number) => {
+                let Some(keys) = order_keys(&values) else {
+                    return values.into_iter().next();
+                };
+                let min = *self == Self::Min;
+                let ind = (0..keys.len()).reduce(|a, b| {
+                    if (min && keys[b] < keys[a]) || (!min && keys[b] > keys[a]) {
+                        b
+                    } else {
+                        a
+                    }
+                })?;
+                values.into_iter().nth(ind)
+            }
+            Self::Sum | Self::Mean if !values.iter().any(is_number) => values.into_iter().next(),
+            Self::Count => Some(Attribute::Integer(values.len() as i64)),
+            Self::Sum => {
+                if values.iter().all(|v| matches!(v, Attribute::Integer(_))) {
+                    let sum = values
+                        .iter()
+                        .filter_map(i64::from_attr_relaxed)
+                        .sum::<i64>();
+                    Some(Attribute::Integer(sum))
+                } else {
+                    Some(Attribute::Float(floats(&values).iter().sum()))
+                }
+            }
+            Self::Min | Self::Max => {
+                let min = *self == Self::Min;
+                if values.iter().all(|v| matches!(v, Attribute::Integer(_))) {
+                    values
+                        .iter()
+                        .filter_map(i64::from_attr_relaxed)
+                        .reduce(|a, b| if min { a.min(b) } else { a.max(b) })
+                        .map(Attribute::Integer)
+                } else {
+                    floats(&values)
+                        .into_iter()
+                        .reduce(|a, b| if min { a.min(b) } else { a.max(b) })
+                        .map(Attribute::Float)
+                }
+            }
+            Self::List => Some(Attribute::Array(values.into())),
+            Self::Mean => {
+                let vals = floats(&values);
+                if vals.is_empty() {
+                    None
+                } else {
+                    Some(Attribute::Float(
+                        vals.iter().sum::<f64>() / vals.len() as f64,
+                    ))
+                }
+            }
+        }
+    }
+}
+
+fn is_number(value: &Attribute) -> bool {
+    matches!(value, Attribute::Integer(_) | Attribute::Float(_))
+}
+
+/// Keys to order the values by, if they are all dates, date times or strings
+fn order_keys(values: &[Attribute]) -> Option<Vec<String>> {
+    let kind = std::mem::discriminant(values.first()?);
+    values
+        .iter()
+        .map(|v| {
+            if std::mem::discriminant(v) != kind {
+                return None;
+            }
+            match v {
+                // the ISO format sorts in chronological order
+                Attribute::Date(d) => Some(d.to_string()),
+                Attribute::DateTime(d) => Some(d.to_string()),
+                Attribute::String(s) => Some(s.to_string()),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Numeric values, ignoring the ones that can't be converted
+fn floats(values: &[Attribute]) -> Vec<f64> {
+    values.iter().filter_map(f64::from_attr_relaxed).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nadi_core::attrs::Date;
+
+    fn strings(values: &[&str]) -> Vec<Attribute> {
+        values
+            .iter()
+            .map(|v| Attribute::String((*v).into()))
+            .collect()
+    }
+
+    #[test]
+    fn numeric() {
+        let values = vec![
+            Attribute::Integer(3),
+            Attribute::Integer(1),
+            Attribute::Integer(2),
+        ];
+        assert_eq!(
+            Aggregate::Sum.apply(values.clone()),
+            Some(Attribute::Integer(6))
+        );
+        assert_eq!(
+            Aggregate::Min.apply(values.clone()),
+            Some(Attribute::Integer(1))
+        );
+        assert_eq!(
+            Aggregate::Max.apply(values.clone()),
+            Some(Attribute::Integer(3))
+        );
+        assert_eq!(Aggregate::Mean.apply(values), Some(Attribute::Float(2.0)));
+        assert_eq!(Aggregate::Sum.apply(vec![]), None);
+    }
+
+    #[test]
+    fn text() {
+        let values = strings(&["HUC12", "HUC08", "HUC10"]);
+        assert_eq!(
+            Aggregate::Sum.apply(values.clone()),
+            Some(Attribute::String("HUC12".into()))
+        );
+        assert_eq!(
+            Aggregate::Mean.apply(values.clone()),
+            Some(Attribute::String("HUC12".into()))
+        );
+        assert_eq!(
+            Aggregate::Min.apply(values.clone()),
+            Some(Attribute::String("HUC08".into()))
+        );
+        assert_eq!(
+            Aggregate::Max.apply(values),
+            Some(Attribute::String("HUC12".into()))
+        );
+    }
+
+    #[test]
+    fn dates() {
+        let values = vec![
+            Attribute::Date(Date::new(2001, 5, 20)),
+            Attribute::Date(Date::new(1998, 11, 2)),
+            Attribute::Date(Date::new(2001, 10, 1)),
+        ];
+        assert_eq!(
+            Aggregate::Min.apply(values.clone()),
+            Some(Attribute::Date(Date::new(1998, 11, 2)))
+        );
+        assert_eq!(
+            Aggregate::Max.apply(values),
+            Some(Attribute::Date(Date::new(2001, 10, 1)))
+        );
+    }
+
+    #[test]
+    fn mixed_types() {
+        let values = vec![Attribute::Bool(true), Attribute::String("a".into())];
+        assert_eq!(Aggregate::Min.apply(values), Some(Attribute::Bool(true)));
+    }
+}
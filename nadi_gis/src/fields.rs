@@ -0,0 +1,146 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use nadi_core::anyhow::{Context, Result};
+
+/// Maximum length of field names supported by the driver
+pub fn field_name_limit(driver: &str) -> Option<usize> {
+    match driver {
+        // dbf files only support 10 characters
+        "ESRI Shapefile" => Some(10),
+        _ => None,
+    }
+}
+
+/// Shorten the names to the limit, keeping them unique
+pub fn short_field_names(names: &[&str], limit: Option<usize>) -> Vec<String> {
+    let Some(limit) = limit else {
+        return names.iter().map(|n| n.to_string()).collect();
+    };
+    let mut used = HashSet::new();
+    names
+        .iter()
+        .map(|n| {
+            let mut name: String = n.chars().take(limit).collect();
+            let mut i = 1;
+            // field names are case insensitive in dbf
+            while !used.insert(name.to_lowercase()) {
+                let suffix = format!("_{i}");
+                let base: String = n.chars().take(limit.saturating_sub(suffix.len())).collect();
+                name = format!("{base}{suffix}");
+                i += 1;
+            }
+            name
+        })
+        .collect()
+}
+
+pub fn field_map_path(file: &Path) -> PathBuf {
+    file.with_extension("fieldmap.csv")
+}
+
+/// Save the mapping of the shortened field names for the layer
+///
+/// Old mappings of the layer are always removed, so they don't
+/// apply to a layer saved again without renames. Mappings of
+/// other layers already in the file are kept, and the file is
+/// removed when no mappings are left.
+pub fn write_field_map(file: &Path, layer: &str, attrs: &[&str], fields: &[String]) -> Result<()> {
+    let path = field_map_path(file);
+    let mut rows: Vec<String> = if path.exists() {
+        std::fs::read_to_string(&path)?
+            .lines()
+            .skip(1)
+            .filter(|l| l.split(',').next() != Some(layer))
+            .map(String::from)
+            .collect()
+    } else {
+        Vec::new()
+    };
+    for (field, attr) in fields.iter().zip(attrs) {
+        if field != attr {
+            rows.push(format!("{layer},{field},{attr}"));
+        }
+    }
+    if rows.is_empty() {
+        if path.exists() {
+            std::fs::remove_file(&path).context(format!("Could not remove {path:?}"))?;
+        }
+        return Ok(());
+    }
+    let mut contents = String::from("layer,field,attribute\n");
+    for row in rows {
+        contents.push_str(&row);
+        contents.push('\n');
+    }
+    std::fs::write(&path, contents).context(format!("Could not write {path:?}"))?;
+    Ok(())
+}
+
+/// Read the field to attribute name mapping for the layer
+pub fn read_field_map(path: &Path, layer: &str) -> Result<HashMap<String, String>> {
+    let contents = std::fs::read_to_string(path).context(format!("Could not read {path:?}"))?;
+    Ok(contents
+        .lines()
+        .skip(1)
+        .filter_map(|l| {
+            let mut row = l.splitn(3, ',');
+            match (row.next(), row.next(), row.next()) {
+                (Some(lyr), Some(f), Some(a)) if lyr == layer && f != a => {
+                    Some((f.to_string(), a.to_string()))
+                }
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_names_unique() {
+        let names = ["drainage_area", "drainage_length", "Drainage_Area", "name"];
+        assert_eq!(
+            short_field_names(&names, Some(10)),
+            vec!["drainage_a", "drainage_l", "Drainage_1", "name"]
+        );
+        assert_eq!(short_field_names(&names, None), names);
+    }
+
+    #[test]
+    fn field_map_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("nadi-gis-fieldmap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("nodes.shp");
+        let attrs = ["drainage_area", "name"];
+        let fields = short_field_names(&attrs, Some(10));
+        write_field_map(&file, "nodes", &attrs, &fields).unwrap();
+        write_field_map(&file, "other", &attrs, &fields).unwrap();
+        let map = read_field_map(&field_map_path(&file), "nodes").unwrap();
+        assert_eq!(map.len(), 1);
+        assert_eq!(map["drainage_a"], "drainage_area");
+
+        // saving without renames removes the old mappings of the layer only
+        write_field_map(
+            &file,
+            "nodes",
+            &attrs,
+            &["drainage_area".into(), "name".into()],
+        )
+        .unwrap();
+        assert!(read_field_map(&field_map_path(&file), "nodes")
+            .unwrap()
+            .is_empty());
+        write_field_map(
+            &file,
+            "other",
+            &attrs,
+            &["drainage_area".into(), "name".into()],
+        )
+        .unwrap();
+        assert!(!field_map_path(&file).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,24 +1,74 @@
 use nadi_core::nadi_plugin::nadi_plugin;
 
+mod aggregate;
+mod fields;
+mod list;
+mod network;
+
 #[nadi_plugin]
 mod nadi_gis {
+    use super::aggregate::Aggregate;
+    use super::fields::{
+        field_map_path, field_name_limit, read_field_map, short_field_names, write_field_map,
+    };
+    use super::list::{Merge, SyncOptions};
+    use super::network::{line_parts, points_transform, Snap, StreamNetwork};
     use chrono::Datelike;
-    use gdal::vector::{FieldValue, Geometry, LayerAccess, LayerOptions, OGRFieldType};
-    use gdal::{Dataset, DriverManager, DriverType};
+    use gdal::spatial_ref::{AxisMappingStrategy, CoordTransform, SpatialRef};
+    use gdal::vector::{
+        field_type_to_name, FieldValue, Geometry, Layer, LayerAccess, LayerOptions, OGRFieldType,
+    };
+    use gdal::{Dataset, DatasetOptions, DriverManager, DriverType, GdalOpenFlags, Metadata};
     use nadi_core::abi_stable::std_types::{RSome, RString};
-    use nadi_core::anyhow::{Context, Result};
+    use nadi_core::anyhow::{bail, Context, Result};
     use nadi_core::attrs::{Date, DateTime, FromAttribute, FromAttributeRelaxed, HasAttributes};
     use nadi_core::functions::Propagation;
     use nadi_core::nadi_plugin::network_func;
     use nadi_core::prelude::*;
+    use nadi_core::timeseries::HasTimeSeries;
     use std::collections::{HashMap, HashSet};
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
 
     /// Load node attributes from a GIS file
     ///
     /// The function reads a GIS file in any format (CSV, GPKG, SHP,
     /// JSON, etc) and loads their fields as attributes to the nodes.
-    #[network_func(geometry = "GEOM", ignore = "", sanitize = true, err_no_node = false)]
+    ///
+    /// Fields shortened while saving by `gis_save_nodes` are renamed
+    /// back using `fieldmap`, or the `.fieldmap.csv` file next to
+    /// the `file` if it exists.
+    ///
+    /// List fields are loaded as arrays and date time fields as
+    /// DateTime, while time fields are loaded as strings. Fields of
+    /// other types (e.g. binary) are skipped, use `warn_skipped` or
+    /// `err_skipped` to know which ones.
+    ///
+    /// When multiple features correspond to the same node, the last
+    /// one overwrites the attributes, unless an `aggregate` rule
+    /// (first/sum/min/max/mean/count/list) is given for the attribute.
+    ///
+    /// The features can be filtered before loading with an OGR SQL
+    /// where clause (`query`), a bounding box, polygons from another
+    /// GIS file (`within`), or the extent of the network geometries.
+    ///
+    /// The CRS of the layer (or `target_crs` if the geometries are
+    /// reprojected) is saved as WKT in the network attribute
+    /// `{geometry}_CRS`, which the save functions use as the source
+    /// CRS of the geometries. Loading the geometries in a different
+    /// CRS than the one already in the network is an error, as the
+    /// nodes loaded before would be in the other CRS; reproject them
+    /// to the network's CRS with `target_crs` instead.
+    #[network_func(
+        geometry = "GEOM",
+        ignore = "",
+        sanitize = true,
+        err_no_node = false,
+        warn_skipped = false,
+        err_skipped = false,
+        network_extent = false,
+        report = false,
+        aggregate = HashMap::new()
+    )]
     fn gis_load_attrs(
         net: &mut Network,
         /// GIS file to load (can be any format GDAL can understand)
@@ -35,27 +85,119 @@ mod nadi_gis {
         sanitize: bool,
         /// Error if all nodes are not found in the GIS file
         err_no_node: bool,
+        /// CSV file with the original names of shortened fields
+        fieldmap: Option<PathBuf>,
+        /// CRS to reproject the geometries to (e.g. EPSG:4326)
+        target_crs: Option<String>,
+        /// Warn about the fields skipped due to unsupported types
+        warn_skipped: bool,
+        /// Error if any fields are skipped due to unsupported types
+        err_skipped: bool,
+        /// OGR SQL where clause to filter the features
+        query: Option<String>,
+        /// Bounding box to filter the features [xmin, ymin, xmax, ymax]
+        bbox: Option<Vec<f64>>,
+        /// GIS file with polygons to filter the features within
+        within: Option<PathBuf>,
+        /// Filter the features within the extent of the network geometries
+        network_extent: bool,
+        /// Print the number of features loaded and nodes without features
+        report: bool,
+        /// Aggregation of attributes for nodes with multiple features
+        aggregate: HashMap<String, String>,
     ) -> Result<()> {
-        let data = Dataset::open(file)?;
-        let mut lyr = if let Some(lyr) = layer {
-            data.layer_by_name(&lyr)
-                .context("Given Layer doesn't exist")?
-        } else {
-            if data.layer_count() > 1 {
-                eprintln!("WARN Multiple layers found, you can choose a specific layer");
-                eprint!("WARN Available Layers:");
-                data.layers().for_each(|l| eprint!(" {:?}", l.name()));
-                eprintln!();
+        let aggregate: HashMap<String, Aggregate> = aggregate
+            .into_iter()
+            .map(|(k, v)| Ok((k, Aggregate::from_name(&v)?)))
+            .collect::<Result<_, String>>()
+            .map_err(nadi_core::anyhow::Error::msg)?;
+        let data = Dataset::open(&file)?;
+        let mut lyr = open_layer(&data, layer)?;
+        if let Some(q) = query {
+            lyr.set_attribute_filter(&q)
+                .context(format!("Invalid attribute filter {q:?}"))?;
+        }
+        if let Some(geom) = spatial_filter(net, &geometry, &lyr, bbox, within, network_extent)? {
+            lyr.set_spatial_filter(&geom);
+        }
+        let (crs, transform) = match (lyr.spatial_ref(), target_crs) {
+            (Some(mut src), Some(t)) => {
+                src.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+                let t = spatial_ref(&t)?;
+                let ct = CoordTransform::new(&src, &t)?;
+                (Some(t), Some(ct))
             }
-            data.layer(0)?
+            (None, Some(_)) => bail!("Layer has no CRS, cannot reproject to target_crs"),
+            (src, None) => (src, None),
+        };
+        if let Some(crs) = crs {
+            let wkt = crs.to_wkt()?;
+            if let Some(old) = net.attr(&crs_attr(&geometry)) {
+                let old = String::try_from_attr(old).map_err(nadi_core::anyhow::Error::msg)?;
+                if spatial_ref(&old)? != spatial_ref(&wkt)? {
+                    bail!(
+                        "CRS of the geometries differs from the network attribute {}, \
+                         use target_crs to reproject them to the same CRS",
+                        crs_attr(&geometry)
+                    );
+                }
+            }
+            net.set_attr(&crs_attr(&geometry), Attribute::String(wkt.into()));
+        }
+        let fieldmap = match fieldmap {
+            Some(fm) => read_field_map(&fm, &lyr.name())?,
+            None if field_map_path(&file).exists() => {
+                read_field_map(&field_map_path(&file), &lyr.name())?
+            }
+            None => HashMap::new(),
         };
 
         let ignore: HashSet<String> = ignore.split(',').map(String::from).collect();
+        let mut time_fields: Vec<String> = Vec::new();
+        let mut skipped: Vec<String> = Vec::new();
+        for fd in lyr.defn().fields() {
+            let name = fd.name();
+            if ignore.contains(&name) {
+                continue;
+            }
+            match fd.field_type() {
+                OGRFieldType::OFTTime => time_fields.push(name),
+                OGRFieldType::OFTInteger
+                | OGRFieldType::OFTIntegerList
+                | OGRFieldType::OFTInteger64
+                | OGRFieldType::OFTInteger64List
+                | OGRFieldType::OFTReal
+                | OGRFieldType::OFTRealList
+                | OGRFieldType::OFTString
+                | OGRFieldType::OFTStringList
+                | OGRFieldType::OFTDate
+                | OGRFieldType::OFTDateTime => (),
+                t => skipped.push(format!("{name} ({})", field_type_to_name(t))),
+            }
+        }
+        if !skipped.is_empty() {
+            let msg = format!(
+                "Fields with unsupported types skipped: {}",
+                skipped.join(", ")
+            );
+            if err_skipped {
+                bail!(msg);
+            } else if warn_skipped {
+                eprintln!("WARN {msg}");
+            }
+        }
 
+        let mut features = 0;
+        let mut found: HashSet<String> = HashSet::new();
+        let mut collected: HashMap<(String, String), Vec<Attribute>> = HashMap::new();
         for f in lyr.features() {
+            features += 1;
             let name = f.field_as_string_by_name(&node)?.unwrap_or("".to_string());
             let n = match net.node_by_name(&name) {
-                Some(n) => n,
+                Some(n) => {
+                    found.insert(name.clone());
+                    n
+                }
                 None if err_no_node => {
                     return Err(nadi_core::anyhow::Error::msg(format!(
                         "Node {name} not found"
@@ -63,44 +205,675 @@ mod nadi_gis {
                 }
                 None => continue,
             };
-            if let Some(g) = f.geometry().and_then(|g| g.wkt().ok()) {
-                n.lock().set_attr(&geometry, Attribute::String(g.into()));
+            if let Some(g) = f.geometry() {
+                let g = match &transform {
+                    Some(ct) => g.transform(ct)?.wkt(),
+                    None => g.wkt(),
+                };
+                if let Ok(g) = g {
+                    n.lock().set_attr(&geometry, Attribute::String(g.into()));
+                }
             }
             let attrs = f
                 .fields()
                 .filter(|(f, _)| !ignore.contains(f))
                 .filter_map(|(f, v)| {
-                    let f = if sanitize { sanitize_key(&f) } else { f };
-                    let f = RString::from(f);
-                    if let Some(val) = v {
-                        match val {
-                            FieldValue::IntegerValue(i) => Some((f, Attribute::Integer(i as i64))),
-                            FieldValue::Integer64Value(i) => Some((f, Attribute::Integer(i))),
-                            FieldValue::StringValue(i) => {
-                                Some((f, Attribute::String(RString::from(i))))
-                            }
-                            FieldValue::RealValue(i) => Some((f, Attribute::Float(i))),
-                            FieldValue::DateValue(d) => Some((
-                                f,
-                                Attribute::Date(Date::new(
-                                    d.year() as u16,
-                                    d.month() as u8,
-                                    d.day() as u8,
-                                )),
-                            )),
-                            _ => None,
-                        }
-                    } else {
-                        None
-                    }
+                    let f = match fieldmap.get(&f) {
+                        Some(orig) => orig.to_string(),
+                        None if sanitize => sanitize_key(&f),
+                        None => f,
+                    };
+                    Some((RString::from(f), field_to_attr(v?)?))
                 });
+            let (agg, attrs): (Vec<_>, Vec<_>) =
+                attrs.partition(|(k, _)| aggregate.contains_key(k.as_str()));
+            for (k, v) in agg {
+                collected
+                    .entry((name.clone(), k.to_string()))
+                    .or_default()
+                    .push(v);
+            }
             n.lock().attr_map_mut().extend(attrs);
+            for tf in &time_fields {
+                if let Some(t) = f.field_as_string_by_name(tf)? {
+                    let k = fieldmap.get(tf).cloned().unwrap_or_else(|| {
+                        if sanitize {
+                            sanitize_key(tf)
+                        } else {
+                            tf.to_string()
+                        }
+                    });
+                    n.lock().set_attr(&k, Attribute::String(t.into()));
+                }
+            }
+        }
+        for ((name, k), vals) in collected {
+            if let (Some(n), Some(v)) = (net.node_by_name(&name), aggregate[&k].apply(vals)) {
+                n.lock().set_attr(&k, v);
+            }
+        }
+        if report {
+            println!(
+                "{features} features read, {} matched nodes, {} nodes without features",
+                found.len(),
+                net.nodes_count() - found.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Validate a GIS file against the network before loading it
+    ///
+    /// Returns a table with the node names from the GIS file missing
+    /// in the network (`missing_nodes`), the network nodes missing in
+    /// the GIS file (`missing_features`), the node names repeated in
+    /// multiple features with their counts (`duplicates`), and the
+    /// fields whose values have different types between features
+    /// along with the types seen (`inconsistent_fields`).
+    ///
+    /// String values are checked for the type of their content, so
+    /// CSV fields mixing numbers and text are also reported.
+    #[network_func(print = true)]
+    fn gis_validate(
+        net: &mut Network,
+        /// GIS file to validate (can be any format GDAL can understand)
+        file: PathBuf,
+        /// Field in the GIS file corresponding to node name
+        node: String,
+        /// layer of the GIS file, first one picked by default
+        layer: Option<String>,
+        /// OGR SQL where clause to filter the features
+        query: Option<String>,
+        /// Print the report
+        print: bool,
+    ) -> Result<Attribute> {
+        let data = Dataset::open(&file)?;
+        let mut lyr = open_layer(&data, layer)?;
+        if let Some(q) = query {
+            lyr.set_attribute_filter(&q)
+                .context(format!("Invalid attribute filter {q:?}"))?;
+        }
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        let mut order: Vec<String> = Vec::new();
+        let mut types: HashMap<String, Vec<&'static str>> = HashMap::new();
+        for f in lyr.features() {
+            let name = f.field_as_string_by_name(&node)?.unwrap_or("".to_string());
+            let c = counts.entry(name.clone()).or_insert(0);
+            if *c == 0 {
+                order.push(name);
+            }
+            *c += 1;
+            for (k, v) in f.fields() {
+                if let Some(v) = v {
+                    if matches!(&v, FieldValue::StringValue(s) if s.trim().is_empty()) {
+                        continue;
+                    }
+                    let tys = types.entry(k).or_default();
+                    let t = value_kind(&v);
+                    if !tys.contains(&t) {
+                        tys.push(t);
+                    }
+                }
+            }
+        }
+        let missing_nodes: Vec<&String> = order
+            .iter()
+            .filter(|n| net.node_by_name(n).is_none())
+            .collect();
+        let missing_features: Vec<String> = net
+            .nodes()
+            .map(|n| n.lock().name().to_string())
+            .filter(|n| !counts.contains_key(n))
+            .collect();
+        let duplicates: Vec<(&String, usize)> = order
+            .iter()
+            .map(|n| (n, counts[n]))
+            .filter(|(_, c)| *c > 1)
+            .collect();
+        let mut inconsistent: Vec<(String, Vec<&str>)> =
+            types.into_iter().filter(|(_, t)| t.len() > 1).collect();
+        inconsistent.sort();
+
+        if print {
+            println!(
+                "{} features, {} node names from the file not in the network: {}",
+                counts.values().sum::<usize>(),
+                missing_nodes.len(),
+                missing_nodes
+                    .iter()
+                    .map(|s| s.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            println!(
+                "{} network nodes not in the file: {}",
+                missing_features.len(),
+                missing_features.join(", ")
+            );
+            println!("{} duplicate node names:", duplicates.len());
+            for (n, c) in &duplicates {
+                println!("  {n} ({c} features)");
+            }
+            println!("{} fields with inconsistent types:", inconsistent.len());
+            for (f, t) in &inconsistent {
+                println!("  {f} ({})", t.join(", "));
+            }
+        }
+
+        let strings = |v: &mut dyn Iterator<Item = &str>| {
+            Attribute::Array(
+                v.map(|s| Attribute::String(s.into()))
+                    .collect::<Vec<_>>()
+                    .into(),
+            )
+        };
+        let mut report = AttrMap::new();
+        report.insert(
+            "missing_nodes".into(),
+            strings(&mut missing_nodes.iter().map(|s| s.as_str())),
+        );
+        report.insert(
+            "missing_features".into(),
+            strings(&mut missing_features.iter().map(|s| s.as_str())),
+        );
+        report.insert(
+            "duplicates".into(),
+            Attribute::Table(
+                duplicates
+                    .into_iter()
+                    .map(|(n, c)| (RString::from(n.as_str()), Attribute::Integer(c as i64)))
+                    .collect(),
+            ),
+        );
+        report.insert(
+            "inconsistent_fields".into(),
+            Attribute::Table(
+                inconsistent
+                    .into_iter()
+                    .map(|(f, t)| (RString::from(f), strings(&mut t.into_iter())))
+                    .collect(),
+            ),
+        );
+        Ok(Attribute::Table(report))
+    }
+
+    /// Load the network from the stream lines and points of interest
+    ///
+    /// The points (gauges, outlets, etc) are snapped to the nearest
+    /// stream line, and then traced downstream through the stream
+    /// lines to find the next point, which becomes the output of the
+    /// node. The stream lines should be digitized in the flow
+    /// direction, and connected reaches should share their end
+    /// points. Distances are in the units of the stream lines' CRS,
+    /// and the points are reprojected to it if they are in another.
+    ///
+    /// This replaces the current network; the geometry of the points
+    /// are saved in the `geometry` attribute like in `gis_load_attrs`.
+    /// The network is built from the connections, so the points not
+    /// connected to any other point are dropped from it.
+    #[network_func(geometry = "GEOM")]
+    fn gis_load_network(
+        net: &mut Network,
+        /// GIS file with the stream lines
+        streams: PathBuf,
+        /// GIS file with the points of interest
+        points: PathBuf,
+        /// Field in the points file corresponding to node name
+        node: String,
+        /// layer of the stream lines file, first one picked by default
+        streams_layer: Option<String>,
+        /// layer of the points file, first one picked by default
+        points_layer: Option<String>,
+        /// Attribute to save the GIS geometry of points in
+        geometry: String,
+        /// Maximum distance to snap the points to the stream lines
+        tolerance: Option<f64>,
+        /// Attribute to save the stream path from node to its output in
+        reach: Option<String>,
+    ) -> Result<()> {
+        let tolerance = tolerance.unwrap_or(f64::INFINITY);
+        let data = Dataset::open(streams)?;
+        let mut lyr = open_layer(&data, streams_layer)?;
+        let streams_crs = lyr.spatial_ref();
+        let reaches: Vec<Vec<_>> = lyr
+            .features()
+            .filter_map(|f| f.geometry().map(line_parts))
+            .flatten()
+            .collect();
+        let streams = StreamNetwork::new(reaches);
+        if streams.reaches_count() == 0 {
+            bail!("No stream lines found in the streams file");
+        }
+
+        let data = Dataset::open(points)?;
+        let mut lyr = open_layer(&data, points_layer)?;
+        let points_crs = lyr.spatial_ref();
+        if points_crs.is_some() != streams_crs.is_some() {
+            eprintln!("WARN CRS of the points or stream lines unknown, assuming they are the same");
+        }
+        // CRS of the saved geometries, the points are reprojected to
+        // the stream lines' CRS if it's known
+        let crs = streams_crs
+            .as_ref()
+            .or(points_crs.as_ref())
+            .map(|c| c.to_wkt())
+            .transpose()?;
+        let transform = points_transform(points_crs, streams_crs)?;
+        let mut names: Vec<String> = Vec::new();
+        let mut geoms: Vec<String> = Vec::new();
+        let mut snaps: Vec<Snap> = Vec::new();
+        for f in lyr.features() {
+            let name = f.field_as_string_by_name(&node)?.unwrap_or("".to_string());
+            let Some(g) = f.geometry() else {
+                eprintln!("WARN Point {name:?} has no geometry");
+                continue;
+            };
+            let g = match &transform {
+                Some(ct) => g.transform(ct)?,
+                None => g.clone(),
+            };
+            let (x, y, _) = g.get_point(0);
+            match streams.snap((x, y), tolerance) {
+                Some(s) => {
+                    names.push(name);
+                    geoms.push(g.wkt()?);
+                    snaps.push(s);
+                }
+                None => eprintln!("WARN Point {name:?} is not within tolerance of stream lines"),
+            }
+        }
+
+        let downstream = streams.trace(&snaps);
+        let edges: Vec<(&str, &str)> = downstream
+            .iter()
+            .enumerate()
+            .filter_map(|(i, d)| {
+                d.as_ref()
+                    .map(|(j, _)| (names[i].as_str(), names[*j].as_str()))
+            })
+            .collect();
+        let connected: HashSet<&str> = edges.iter().flat_map(|(s, e)| [*s, *e]).collect();
+        names
+            .iter()
+            .filter(|n| !connected.contains(n.as_str()))
+            .for_each(|n| {
+                eprintln!("WARN Point {n:?} is not connected to any other points, dropped")
+            });
+        if edges.is_empty() {
+            bail!("None of the points are connected, network needs at least two connected points");
+        }
+
+        *net = Network::from_edges(&edges).map_err(nadi_core::anyhow::Error::msg)?;
+        if let Some(crs) = crs {
+            net.set_attr(&crs_attr(&geometry), Attribute::String(crs.into()));
+        }
+        for (name, geom) in names.iter().zip(geoms) {
+            if let Some(n) = net.node_by_name(name) {
+                n.lock().set_attr(&geometry, Attribute::String(geom.into()));
+            }
+        }
+        if let Some(reach) = reach {
+            for (name, d) in names.iter().zip(downstream) {
+                let (Some(n), Some((_, path))) = (net.node_by_name(name), d) else {
+                    continue;
+                };
+                let mut line = Geometry::empty(gdal_sys::OGRwkbGeometryType::wkbLineString)?;
+                path.into_iter().for_each(|p| line.add_point_2d(p));
+                n.lock()
+                    .set_attr(&reach, Attribute::String(line.wkt()?.into()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Load node attributes from a GIS file based on their location
+    ///
+    /// The nodes are matched to the features using their geometry
+    /// instead of the names. With `method` "within" the features
+    /// (polygons like HUCs, counties, etc) containing the node are
+    /// matched, "nearest" matches the nearest feature within the
+    /// `distance`, and "distance" matches all features within the
+    /// `distance`. When multiple features match, their values are
+    /// combined with the `aggregate` rule: first/sum/mean/count; the
+    /// fields without numeric values take the first value for sum and
+    /// mean.
+    ///
+    /// The node geometries are reprojected to the layer's CRS if the
+    /// network has the CRS of the `geometry` (see `gis_load_attrs`).
+    #[network_func(
+        geometry = "GEOM",
+        fields = Vec::new(),
+        method = "within",
+        aggregate = "first",
+        prefix = ""
+    )]
+    fn gis_spatial_join(
+        net: &mut Network,
+        /// GIS file to load (can be any format GDAL can understand)
+        file: PathBuf,
+        /// layer of the GIS file, first one picked by default
+        layer: Option<String>,
+        /// Attribute with the GIS geometry of the nodes
+        geometry: String,
+        /// Fields to load, all fields are loaded if empty
+        fields: Vec<String>,
+        /// Method to match the features: within/nearest/distance
+        method: String,
+        /// Maximum distance for nearest/distance methods
+        distance: Option<f64>,
+        /// Aggregation for multiple matches: first/sum/mean/count
+        aggregate: String,
+        /// Prefix to add to the field names for the attributes
+        prefix: String,
+    ) -> Result<()> {
+        let agg = Aggregate::from_name(&aggregate).map_err(nadi_core::anyhow::Error::msg)?;
+        let distance = match (method.as_str(), distance) {
+            ("within", _) => 0.0,
+            ("nearest" | "distance", Some(d)) => d,
+            ("nearest" | "distance", None) => bail!("Method {method:?} requires `distance`"),
+            (m, _) => bail!("Unknown method {m:?}, use within, nearest or distance"),
+        };
+        let data = Dataset::open(&file)?;
+        let mut lyr = open_layer(&data, layer)?;
+        let transform = layer_transform(net, &geometry, &lyr)?;
+
+        for node in net.nodes() {
+            let mut n = node.lock();
+            let Some(g) = n.attr(&geometry) else {
+                continue;
+            };
+            let g = String::try_from_attr(g).map_err(nadi_core::anyhow::Error::msg)?;
+            let mut g = Geometry::from_wkt(&g)?;
+            if let Some(ct) = &transform {
+                g.transform_inplace(ct)?;
+            }
+            let env = g.envelope();
+            lyr.set_spatial_filter_rect(
+                env.MinX - distance,
+                env.MinY - distance,
+                env.MaxX + distance,
+                env.MaxY + distance,
+            );
+            let mut matches: Vec<(f64, Vec<(String, Attribute)>)> = Vec::new();
+            for f in lyr.features() {
+                let Some(fg) = f.geometry() else {
+                    continue;
+                };
+                let dist = if method == "within" {
+                    if !fg.contains(&g) {
+                        continue;
+                    }
+                    0.0
+                } else {
+                    let d = geometry_distance(&g, fg)?;
+                    if d > distance {
+                        continue;
+                    }
+                    d
+                };
+                let vals = f
+                    .fields()
+                    .filter(|(k, _)| fields.is_empty() || fields.contains(k))
+                    .filter_map(|(k, v)| Some((k, field_to_attr(v?)?)))
+                    .collect();
+                matches.push((dist, vals));
+            }
+            if method == "nearest" {
+                matches.sort_by(|a, b| a.0.total_cmp(&b.0));
+                matches.truncate(1);
+            }
+            let mut grouped: Vec<(String, Vec<Attribute>)> = Vec::new();
+            for (k, v) in matches.into_iter().flat_map(|(_, vals)| vals) {
+                match grouped.iter_mut().find(|(g, _)| *g == k) {
+                    Some((_, vals)) => vals.push(v),
+                    None => grouped.push((k, vec![v])),
+                }
+            }
+            for (k, vals) in grouped {
+                if let Some(v) = agg.apply(vals) {
+                    n.set_attr(&format!("{prefix}{}", sanitize_key(&k)), v);
+                }
+            }
+        }
+        lyr.clear_spatial_filter();
+        Ok(())
+    }
+
+    /// Sync the fields of a GIS file into the node attribute files
+    ///
+    /// Each feature's fields are written into a TOML file given by
+    /// the `output` template, rendered with the feature's fields.
+    /// Existing values in the files are replaced, unless a `merge`
+    /// rule (new/old/min/max/sum) is given for that field. Without
+    /// `output`, the fields are only printed.
+    ///
+    /// Use `dry_run` to see the changes without writing the files.
+    #[network_func(
+        ignore = Vec::new(),
+        sanitize = true,
+        verbose = false,
+        dry_run = false,
+        merge = HashMap::new(),
+        key_sep = "::",
+        var_sep = "="
+    )]
+    fn gis_sync_node_files(
+        _net: &mut Network,
+        /// GIS file with the node attributes
+        file: PathBuf,
+        /// layer of the GIS file, first one picked by default
+        layer: Option<String>,
+        /// Template of the output TOML file for each feature
+        output: Option<String>,
+        /// Field to use as id of the feature while printing
+        primary_key: Option<String>,
+        /// Fields to ignore
+        ignore: Vec<String>,
+        /// sanitize the name of the fields
+        sanitize: bool,
+        /// Print the attributes that have been changed
+        verbose: bool,
+        /// Print the changes without writing the files
+        dry_run: bool,
+        /// Rules to merge the field values with the existing ones
+        merge: HashMap<String, String>,
+        /// key separator while printing
+        key_sep: String,
+        /// variable and value separator while printing
+        var_sep: String,
+    ) -> Result<()> {
+        let merge = merge
+            .into_iter()
+            .map(|(k, v)| Ok((k, Merge::from_name(&v)?)))
+            .collect::<Result<_, String>>()
+            .map_err(nadi_core::anyhow::Error::msg)?;
+        let opts = SyncOptions {
+            ignore,
+            key_sep,
+            var_sep,
+            primary_key,
+            sanitize,
+            verbose,
+            dry_run,
+            merge,
+        };
+        let data = Dataset::open(&file)?;
+        let mut lyr = open_layer(&data, layer)?;
+        if let Some(out) = output {
+            let templ = string_template_plus::Template::parse_template(&out)?;
+            opts.update_attrs(&mut lyr, templ)
+        } else {
+            opts.print_attrs(&mut lyr)
+        }
+    }
+
+    /// Calculate the distances along the network for each node
+    ///
+    /// The distance from each node to its output is the length of
+    /// the `reach` geometry (see `gis_load_network`) if available,
+    /// or the straight line distance between their geometries. The
+    /// cumulative distance to the outlet is saved in `outlet_attr`.
+    /// Distances are in the units of the geometries' CRS.
+    #[network_func(
+        geometry = "GEOM",
+        length_attr = "reach_length",
+        outlet_attr = "outlet_distance"
+    )]
+    fn gis_network_distances(
+        net: &mut Network,
+        /// Attribute with the GIS geometry of the nodes
+        geometry: String,
+        /// Attribute with the geometry of the path from node to its output
+        reach: Option<String>,
+        /// Attribute to save the distance to the output in
+        length_attr: String,
+        /// Attribute to save the distance to the outlet in
+        outlet_attr: String,
+    ) -> Result<()> {
+        warn_geographic(net, &geometry)?;
+        let mut lengths: HashMap<String, f64> = HashMap::new();
+        let mut outputs: HashMap<String, String> = HashMap::new();
+        for node in net.nodes() {
+            let mut n = node.lock();
+            let RSome(out) = n.output() else {
+                n.set_attr(&length_attr, Attribute::Float(0.0));
+                continue;
+            };
+            let out_name = out.lock().name().to_string();
+            let reach_geom = reach.as_ref().and_then(|r| n.attr(r));
+            let length = if let Some(rg) = reach_geom {
+                let rg = String::try_from_attr(rg).map_err(nadi_core::anyhow::Error::msg)?;
+                Geometry::from_wkt(&rg)?.length()
+            } else {
+                let start = node_geometry(&n, &geometry)?;
+                let end = node_geometry(&out.lock(), &geometry)?;
+                geometry_distance(&start, &end)?
+            };
+            n.set_attr(&length_attr, Attribute::Float(length));
+            lengths.insert(n.name().to_string(), length);
+            outputs.insert(n.name().to_string(), out_name);
+        }
+        for node in net.nodes() {
+            let mut n = node.lock();
+            let mut total = 0.0;
+            let mut current = n.name().to_string();
+            while let Some(out) = outputs.get(&current) {
+                total += lengths[&current];
+                current = out.to_string();
+            }
+            n.set_attr(&outlet_attr, Attribute::Float(total));
+        }
+        Ok(())
+    }
+
+    /// Calculate the catchment area of each node from a polygon layer
+    ///
+    /// The catchments are matched to the nodes using the `node` field
+    /// if given, or else by the node geometry being inside the
+    /// polygon. Without `node`, the polygons should be incremental
+    /// catchments that don't overlap with one node each; a polygon
+    /// containing multiple nodes is only counted for the first one,
+    /// with a warning. The incremental area (catchment of the node only)
+    /// and the total upstream area are saved as attributes, in the
+    /// units of the layer's CRS multiplied by `scale` (e.g. 1e-6 for
+    /// square kilometers from a CRS in meters).
+    #[network_func(
+        geometry = "GEOM",
+        area_attr = "catchment_area",
+        total_attr = "drainage_area",
+        scale = 1.0
+    )]
+    fn gis_catchment_area(
+        net: &mut Network,
+        /// GIS file with the catchment polygons
+        file: PathBuf,
+        /// layer of the GIS file, first one picked by default
+        layer: Option<String>,
+        /// Field in the GIS file corresponding to node name
+        node: Option<String>,
+        /// Attribute with the GIS geometry of the nodes
+        geometry: String,
+        /// Attribute to save the incremental area in
+        area_attr: String,
+        /// Attribute to save the total upstream area in
+        total_attr: String,
+        /// Factor to multiply the areas with
+        scale: f64,
+    ) -> Result<()> {
+        let data = Dataset::open(&file)?;
+        let mut lyr = open_layer(&data, layer)?;
+        if lyr
+            .spatial_ref()
+            .map(|s| s.is_geographic())
+            .unwrap_or(false)
+        {
+            eprintln!("WARN Catchments are in geographic CRS, areas will be in square degrees");
+        }
+        let mut areas: HashMap<String, f64> = HashMap::new();
+        if let Some(field) = node {
+            for f in lyr.features() {
+                let name = f.field_as_string_by_name(&field)?.unwrap_or("".to_string());
+                if let Some(g) = f.geometry() {
+                    *areas.entry(name).or_default() += g.area() * scale;
+                }
+            }
+        } else {
+            let transform = layer_transform(net, &geometry, &lyr)?;
+            let mut points: Vec<(String, Geometry)> = Vec::new();
+            for node in net.nodes() {
+                let n = node.lock();
+                let mut g = node_geometry(&n, &geometry)?;
+                if let Some(ct) = &transform {
+                    g.transform_inplace(ct)?;
+                }
+                points.push((n.name().to_string(), g));
+            }
+            for f in lyr.features() {
+                let Some(fg) = f.geometry() else {
+                    continue;
+                };
+                let inside: Vec<&str> = points
+                    .iter()
+                    .filter(|(_, g)| fg.contains(g))
+                    .map(|(name, _)| name.as_str())
+                    .collect();
+                let Some(name) = inside.first() else {
+                    continue;
+                };
+                if inside.len() > 1 {
+                    eprintln!(
+                        "WARN Catchment {} contains multiple nodes ({}), area assigned to {name}",
+                        f.fid().unwrap_or_default(),
+                        inside.join(", ")
+                    );
+                }
+                *areas.entry(name.to_string()).or_default() += fg.area() * scale;
+            }
+        }
+        for node in net.nodes() {
+            let mut n = node.lock();
+            let total = upstream_sum(&n, &areas);
+            if let Some(a) = areas.get(n.name()) {
+                n.set_attr(&area_attr, Attribute::Float(*a));
+            }
+            n.set_attr(&total_attr, Attribute::Float(total));
         }
         Ok(())
     }
 
     /// Save GIS file of the connections
-    #[network_func(layer = "network")]
+    ///
+    /// If `reach` is given, the geometry in that attribute of the
+    /// node (path along the river to its output) is used for the
+    /// connection; Points, LineStrings and their Multi- variants are
+    /// joined in order into a single LineString. Nodes without it use
+    /// a straight line from the node to its output.
+    ///
+    /// The `mode` decides what happens when the `file` already exists:
+    /// `overwrite` recreates the file, `append` adds the layer to the
+    /// existing file (error if the layer exists) and `replace` adds
+    /// the layer replacing the one with the same name if present.
+    #[network_func(layer = "network", mode = "overwrite")]
     fn gis_save_connections(
         net: &mut Network,
         file: PathBuf,
@@ -108,18 +881,18 @@ mod nadi_gis {
         driver: Option<String>,
         layer: String,
         filter: Option<Vec<bool>>,
+        /// What to do if the file exists: overwrite/append/replace
+        mode: String,
+        /// Attribute with the geometry of the path from node to its output
+        reach: Option<String>,
+        /// CRS to reproject the geometries to (e.g. EPSG:4326)
+        target_crs: Option<String>,
     ) -> Result<()> {
-        let driver = if let Some(d) = driver {
-            gdal::DriverManager::get_driver_by_name(&d)?
-        } else {
-            DriverManager::get_output_driver_for_dataset_name(&file, DriverType::Vector)
-                .context("Could not detect Driver for filename, try providing `driver` argument.")?
-        };
-
-        // TODO if file already exists add the layer if possible
-        let mut out_data = driver.create_vector_only(&file)?;
+        let (srs, transform) = output_crs(net, &geometry, target_crs)?;
+        let mut out_data = output_dataset(&file, driver, &layer, &mode)?;
         let mut layer = out_data.create_layer(LayerOptions {
             name: &layer,
+            srs: srs.as_ref(),
             ty: gdal_sys::OGRwkbGeometryType::wkbLineString,
             ..Default::default()
         })?;
@@ -140,27 +913,44 @@ mod nadi_gis {
         for node in nodes {
             let n = node.lock();
             if let RSome(out) = n.output() {
-                let start = String::try_from_attr(
-                    n.attr(&geometry)
-                        .context("Attribute for geometry not found")?,
-                )
-                .map_err(nadi_core::anyhow::Error::msg)?;
-                let end = String::try_from_attr(
-                    out.lock()
-                        .attr(&geometry)
-                        .context("Attribute for geometry not found")?,
-                )
-                .map_err(nadi_core::anyhow::Error::msg)?;
-                let start = Geometry::from_wkt(&start)?;
-                let end = Geometry::from_wkt(&end)?;
-
                 let mut edge_geometry =
                     Geometry::empty(gdal_sys::OGRwkbGeometryType::wkbLineString)?;
-                // add all points from start, (so it can be linestring
-                // instead of just point); and add end's first point
-                // only if it's different from last point of start
-                edge_geometry.add_point(start.get_point(0));
-                edge_geometry.add_point(end.get_point(0));
+                let reach_geom = reach.as_ref().and_then(|r| n.attr(r));
+                if let Some(rg) = reach_geom {
+                    let rg = String::try_from_attr(rg).map_err(nadi_core::anyhow::Error::msg)?;
+                    let mut points = line_parts(&Geometry::from_wkt(&rg)?).into_iter().flatten();
+                    if let Some(first) = points.next() {
+                        edge_geometry.add_point_2d(first);
+                        let mut last = first;
+                        for p in points {
+                            // the parts share the end points when connected
+                            if p != last {
+                                edge_geometry.add_point_2d(p);
+                                last = p;
+                            }
+                        }
+                    }
+                } else {
+                    let start = String::try_from_attr(
+                        n.attr(&geometry)
+                            .context("Attribute for geometry not found")?,
+                    )
+                    .map_err(nadi_core::anyhow::Error::msg)?;
+                    let end = String::try_from_attr(
+                        out.lock()
+                            .attr(&geometry)
+                            .context("Attribute for geometry not found")?,
+                    )
+                    .map_err(nadi_core::anyhow::Error::msg)?;
+                    let start = Geometry::from_wkt(&start)?;
+                    let end = Geometry::from_wkt(&end)?;
+
+                    edge_geometry.add_point(start.get_point(0));
+                    edge_geometry.add_point(end.get_point(0));
+                }
+                if let Some(ct) = &transform {
+                    edge_geometry.transform_inplace(ct)?;
+                }
                 layer.create_feature_fields(
                     edge_geometry,
                     &fields,
@@ -175,38 +965,37 @@ mod nadi_gis {
     }
 
     /// Save GIS file of the nodes
-    #[network_func(attrs=HashMap::new(), layer="nodes")]
+    ///
+    /// Field names longer than the driver supports (10 characters
+    /// for shapefiles) are shortened to unique names, and the mapping
+    /// is saved in a `.fieldmap.csv` file next to the `file`, which
+    /// `gis_load_attrs` uses to load them with the original names.
+    ///
+    /// The types of the fields can be given in `attrs` as a map of
+    /// attribute name to type name, or the attributes can be listed
+    /// in `infer` to detect their types from the values in the
+    /// nodes. Arrays and tables are saved as JSON strings, and the
+    /// attributes missing (or not convertible) in a node are NULL.
+    ///
+    /// The `mode` works the same way as in `gis_save_connections`,
+    /// so nodes and connections can be saved in the same file.
+    #[network_func(attrs=HashMap::new(), infer=Vec::new(), layer="nodes", mode = "overwrite")]
     fn gis_save_nodes(
         net: &mut Network,
         file: PathBuf,
         geometry: String,
         attrs: HashMap<String, String>,
+        /// Attributes to save with the field types inferred from values
+        infer: Vec<String>,
         driver: Option<String>,
         layer: String,
         filter: Option<Vec<bool>>,
+        /// What to do if the file exists: overwrite/append/replace
+        mode: String,
+        /// CRS to reproject the geometries to (e.g. EPSG:4326)
+        target_crs: Option<String>,
     ) -> Result<()> {
-        let driver = if let Some(d) = driver {
-            gdal::DriverManager::get_driver_by_name(&d)?
-        } else {
-            DriverManager::get_output_driver_for_dataset_name(&file, DriverType::Vector)
-                .context("Could not detect Driver for filename, try providing `driver` argument.")?
-        };
-
-        // TODO if file already exists add the layer if possible
-        let mut out_data = driver.create_vector_only(&file)?;
-        let mut layer = out_data.create_layer(LayerOptions {
-            name: &layer,
-            ty: gdal_sys::OGRwkbGeometryType::wkbPoint,
-            ..Default::default()
-        })?;
-        let fields: Vec<(String, (u32, Attr2FieldValue))> = attrs
-            .into_iter()
-            .map(|(k, v)| Ok((k, type_name_to_field(&v)?)))
-            .collect::<Result<_, String>>()
-            .map_err(nadi_core::anyhow::Error::msg)?;
-        let field_types: Vec<(&str, u32)> = fields.iter().map(|(k, v)| (k.as_str(), v.0)).collect();
-        // saving shp means field names will be shortened, it'll error later, how do we fix it?
-        layer.create_defn_fields(&field_types)?;
+        let (srs, transform) = output_crs(net, &geometry, target_crs)?;
         let nodes: Vec<&Node> = if let Some(filt) = filter {
             net.nodes()
                 .zip(filt)
@@ -216,6 +1005,41 @@ mod nadi_gis {
         } else {
             net.nodes().collect()
         };
+        let inferred: Vec<(String, String)> = infer
+            .into_iter()
+            .filter(|k| !attrs.contains_key(k))
+            .map(|k| {
+                let ty = infer_field_type(nodes.iter().filter_map(|n| n.lock().attr(&k).cloned()));
+                (k, ty.to_string())
+            })
+            .collect();
+        let fields: Vec<(String, (u32, Attr2FieldValue))> = attrs
+            .into_iter()
+            .chain(inferred)
+            .map(|(k, v)| Ok((k, type_name_to_field(&v)?)))
+            .collect::<Result<_, String>>()
+            .map_err(nadi_core::anyhow::Error::msg)?;
+
+        let mut out_data = output_dataset(&file, driver, &layer, &mode)?;
+        let attr_names: Vec<&str> = fields.iter().map(|(k, _)| k.as_str()).collect();
+        let short_names = short_field_names(
+            &attr_names,
+            field_name_limit(&out_data.driver().short_name()),
+        );
+        let mut layer = out_data.create_layer(LayerOptions {
+            name: &layer,
+            srs: srs.as_ref(),
+            ty: gdal_sys::OGRwkbGeometryType::wkbPoint,
+            ..Default::default()
+        })?;
+        // single layer drivers name the layer after the file
+        write_field_map(&file, &layer.name(), &attr_names, &short_names)?;
+        let field_types: Vec<(&str, u32)> = fields
+            .iter()
+            .zip(&short_names)
+            .map(|((_, v), s)| (s.as_str(), v.0))
+            .collect();
+        layer.create_defn_fields(&field_types)?;
         for node in nodes {
             let n = node.lock();
             let node_geom = String::try_from_attr(
@@ -223,10 +1047,14 @@ mod nadi_gis {
                     .context("Attribute for geometry not found")?,
             )
             .map_err(nadi_core::anyhow::Error::msg)?;
-            let node_geom = Geometry::from_wkt(&node_geom)?;
+            let mut node_geom = Geometry::from_wkt(&node_geom)?;
+            if let Some(ct) = &transform {
+                node_geom.transform_inplace(ct)?;
+            }
             let feat_fields: Vec<(&str, FieldValue)> = fields
                 .iter()
-                .filter_map(|(k, (_, func))| Some((k.as_str(), func(n.attr(k)?))))
+                .zip(&short_names)
+                .filter_map(|((k, (_, func)), s)| Some((s.as_str(), func(n.attr(k)?)?)))
                 .collect();
 
             let field_names: Vec<&str> = feat_fields.iter().map(|(k, _)| *k).collect();
@@ -236,33 +1064,624 @@ mod nadi_gis {
         Ok(())
     }
 
+    /// Save a single feature GIS layer describing the whole network
+    ///
+    /// The geometry is the convex hull (`boundary="hull"`) or the
+    /// union (`boundary="union"`) of the node geometries, or of the
+    /// polygons in the `catchments` file if given. The fields have
+    /// the number of nodes, the outlet name and its coordinates, and
+    /// the network attributes listed in `attrs`.
+    #[network_func(
+        layer = "network",
+        boundary = "hull",
+        attrs = Vec::new(),
+        mode = "overwrite"
+    )]
+    fn gis_save_network(
+        net: &mut Network,
+        file: PathBuf,
+        geometry: String,
+        /// Boundary from the geometries: hull/union
+        boundary: String,
+        /// GIS file with the catchment polygons to use for the boundary
+        catchments: Option<PathBuf>,
+        /// Network attributes to save
+        attrs: Vec<String>,
+        driver: Option<String>,
+        layer: String,
+        /// What to do if the file exists: overwrite/append/replace
+        mode: String,
+        /// CRS to reproject the geometries to (e.g. EPSG:4326)
+        target_crs: Option<String>,
+    ) -> Result<()> {
+        let (srs, transform) = output_crs(net, &geometry, target_crs)?;
+        let mut geoms: Vec<Geometry> = Vec::new();
+        if let Some(catchments) = catchments {
+            let data = Dataset::open(&catchments)?;
+            let mut lyr = open_layer(&data, None)?;
+            let ct = match (lyr.spatial_ref(), &srs) {
+                (Some(mut src), Some(dst)) => {
+                    src.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+                    Some(CoordTransform::new(&src, dst)?)
+                }
+                _ => None,
+            };
+            for f in lyr.features() {
+                if let Some(g) = f.geometry() {
+                    let g = match &ct {
+                        Some(ct) => g.transform(ct)?,
+                        None => g.clone(),
+                    };
+                    geoms.push(g);
+                }
+            }
+        } else {
+            for node in net.nodes() {
+                let mut g = node_geometry(&node.lock(), &geometry)?;
+                if let Some(ct) = &transform {
+                    g.transform_inplace(ct)?;
+                }
+                geoms.push(g);
+            }
+        }
+        let boundary = match boundary.as_str() {
+            "hull" => {
+                let mut all = Geometry::empty(gdal_sys::OGRwkbGeometryType::wkbGeometryCollection)?;
+                for g in geoms {
+                    all.add_geometry(g)?;
+                }
+                all.convex_hull()?
+            }
+            "union" => geoms
+                .into_iter()
+                .try_fold(None, |u: Option<Geometry>, g| match u {
+                    Some(u) => u.union(&g).map(Some).context("Union of geometries failed"),
+                    None => Ok(Some(g)),
+                })?
+                .context("No geometries found for the network")?,
+            b => bail!("Unknown boundary {b:?}, use hull or union"),
+        };
+
+        let outlet = net
+            .nodes()
+            .find(|n| n.lock().output().is_none())
+            .context("Network has no outlet")?;
+        let outlet = outlet.lock();
+        let mut outlet_geom = node_geometry(&outlet, &geometry)?;
+        if let Some(ct) = &transform {
+            outlet_geom.transform_inplace(ct)?;
+        }
+        let (x, y, _) = outlet_geom.get_point(0);
+
+        let mut names: Vec<&str> = vec!["nodes", "outlet", "outlet_x", "outlet_y"];
+        let mut types = vec![
+            OGRFieldType::OFTInteger64,
+            OGRFieldType::OFTString,
+            OGRFieldType::OFTReal,
+            OGRFieldType::OFTReal,
+        ];
+        let mut values = vec![
+            FieldValue::Integer64Value(net.nodes_count() as i64),
+            FieldValue::StringValue(outlet.name().to_string()),
+            FieldValue::RealValue(x),
+            FieldValue::RealValue(y),
+        ];
+        let mut attr_fields: Vec<(&str, FieldValue)> = Vec::new();
+        for a in &attrs {
+            let Some(val) = net.attr(a) else {
+                eprintln!("WARN Network attribute {a:?} not found");
+                continue;
+            };
+            let (ty, func) = type_name_to_field(infer_field_type(std::iter::once(val.clone())))
+                .map_err(nadi_core::anyhow::Error::msg)?;
+            types.push(ty);
+            if let Some(v) = func(val) {
+                attr_fields.push((a.as_str(), v));
+            }
+            names.push(a.as_str());
+        }
+
+        let mut out_data = output_dataset(&file, driver, &layer, &mode)?;
+        let short_names =
+            short_field_names(&names, field_name_limit(&out_data.driver().short_name()));
+        let mut layer = out_data.create_layer(LayerOptions {
+            name: &layer,
+            srs: srs.as_ref(),
+            ty: gdal_sys::OGRwkbGeometryType::wkbUnknown,
+            ..Default::default()
+        })?;
+        write_field_map(&file, &layer.name(), &names, &short_names)?;
+        let field_types: Vec<(&str, u32)> =
+            short_names.iter().map(|s| s.as_str()).zip(types).collect();
+        layer.create_defn_fields(&field_types)?;
+        let mut feat_names: Vec<&str> = short_names[..4].iter().map(|s| s.as_str()).collect();
+        for (a, v) in attr_fields {
+            let ind = names
+                .iter()
+                .position(|n| *n == a)
+                .expect("attr is in names");
+            feat_names.push(short_names[ind].as_str());
+            values.push(v);
+        }
+        layer.create_feature_fields(boundary, &feat_names, &values)?;
+        Ok(())
+    }
+
+    /// Save the node timeseries to a GIS file in long format
+    ///
+    /// Each value of the timeseries is saved as a separate feature
+    /// with the node's geometry, the node name, the time and the
+    /// value. The times are parsed with the date time format of the
+    /// timelines (or `timefmt`) and saved as date time fields, so
+    /// QGIS temporal controller can animate them; they are saved as
+    /// strings if any of the timelines doesn't have a format.
+    #[network_func(layer = "timeseries", mode = "overwrite")]
+    fn gis_save_timeseries(
+        net: &mut Network,
+        file: PathBuf,
+        /// Name of the timeseries to save
+        name: String,
+        geometry: String,
+        /// Format to parse the times with, instead of the timeline's
+        timefmt: Option<String>,
+        driver: Option<String>,
+        layer: String,
+        filter: Option<Vec<bool>>,
+        /// What to do if the file exists: overwrite/append/replace
+        mode: String,
+        /// CRS to reproject the geometries to (e.g. EPSG:4326)
+        target_crs: Option<String>,
+    ) -> Result<()> {
+        let (srs, transform) = output_crs(net, &geometry, target_crs)?;
+        let nodes: Vec<&Node> = if let Some(filt) = filter {
+            net.nodes()
+                .zip(filt)
+                .filter(|(_, f)| *f)
+                .map(|n| n.0)
+                .collect()
+        } else {
+            net.nodes().collect()
+        };
+        let mut series: Vec<(String, Geometry, Vec<String>, Vec<Attribute>)> = Vec::new();
+        let mut formats: Vec<Option<String>> = Vec::new();
+        for node in nodes {
+            let n = node.lock();
+            let Ok(ts) = n.try_ts(&name) else {
+                eprintln!("WARN Node {} doesn't have timeseries {name:?}", n.name());
+                continue;
+            };
+            let timeline = ts.timeline().lock();
+            let fmt = match &timefmt {
+                Some(f) => Some(f.to_string()),
+                None => Some(timeline.datetimefmt().to_string()).filter(|f| !f.is_empty()),
+            };
+            let times: Vec<String> = timeline
+                .str_values()
+                .into_iter()
+                .map(|t| t.to_string())
+                .collect();
+            drop(timeline);
+            let values = ts.series().clone().to_attributes();
+            let mut geom = node_geometry(&n, &geometry)?;
+            if let Some(ct) = &transform {
+                geom.transform_inplace(ct)?;
+            }
+            series.push((n.name().to_string(), geom, times, values));
+            formats.push(fmt);
+        }
+        let value_type = infer_field_type(series.iter().flat_map(|(_, _, _, v)| v.iter().cloned()));
+        let (value_field, value_func) =
+            type_name_to_field(value_type).map_err(nadi_core::anyhow::Error::msg)?;
+        let datetime = formats.iter().all(Option::is_some);
+        let time_field = if datetime {
+            OGRFieldType::OFTDateTime
+        } else {
+            eprintln!("WARN Timelines without date time format, saving the times as strings");
+            OGRFieldType::OFTString
+        };
+
+        let mut out_data = output_dataset(&file, driver, &layer, &mode)?;
+        // one transaction for all the features, if the driver supports it
+        let transaction = unsafe {
+            gdal_sys::GDALDatasetStartTransaction(out_data.c_dataset(), 1)
+                == gdal_sys::OGRErr::OGRERR_NONE
+        };
+        let mut layer = out_data.create_layer(LayerOptions {
+            name: &layer,
+            srs: srs.as_ref(),
+            ty: gdal_sys::OGRwkbGeometryType::wkbPoint,
+            ..Default::default()
+        })?;
+        layer.create_defn_fields(&[
+            ("node", OGRFieldType::OFTString),
+            ("time", time_field),
+            ("value", value_field),
+        ])?;
+        for ((node, geom, times, values), fmt) in series.into_iter().zip(formats) {
+            for (t, v) in times.into_iter().zip(values) {
+                let time = match &fmt {
+                    Some(fmt) if datetime => FieldValue::DateTimeValue(parse_datetime(&t, fmt)?),
+                    _ => FieldValue::StringValue(t),
+                };
+                let mut names = vec!["node", "time"];
+                let mut vals = vec![FieldValue::StringValue(node.clone()), time];
+                // NaN and values that can't be converted are NULL
+                let value = match v {
+                    Attribute::Float(f) if f.is_nan() => None,
+                    v => value_func(&v),
+                };
+                if let Some(v) = value {
+                    names.push("value");
+                    vals.push(v);
+                }
+                layer.create_feature_fields(geom.clone(), &names, &vals)?;
+            }
+        }
+        if transaction {
+            let err = unsafe { gdal_sys::GDALDatasetCommitTransaction(out_data.c_dataset()) };
+            if err != gdal_sys::OGRErr::OGRERR_NONE {
+                bail!("Could not commit the features to {file:?}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Open the given layer, or the first one if not given
+    fn open_layer(data: &Dataset, layer: Option<String>) -> Result<Layer> {
+        Ok(if let Some(lyr) = layer {
+            data.layer_by_name(&lyr)
+                .context("Given Layer doesn't exist")?
+        } else {
+            if data.layer_count() > 1 {
+                eprintln!("WARN Multiple layers found, you can choose a specific layer");
+                eprint!("WARN Available Layers:");
+                data.layers().for_each(|l| eprint!(" {:?}", l.name()));
+                eprintln!();
+            }
+            data.layer(0)?
+        })
+    }
+
+    /// Open the dataset to write the `layer` into based on the `mode`
+    ///
+    /// The returned dataset will not have a layer named `layer`, so
+    /// the caller can create it.
+    fn output_dataset(
+        file: &Path,
+        driver: Option<String>,
+        layer: &str,
+        mode: &str,
+    ) -> Result<Dataset> {
+        let driver = if let Some(d) = driver {
+            gdal::DriverManager::get_driver_by_name(&d)?
+        } else {
+            DriverManager::get_output_driver_for_dataset_name(file, DriverType::Vector)
+                .context("Could not detect Driver for filename, try providing `driver` argument.")?
+        };
+        match mode {
+            "overwrite" => return Ok(driver.create_vector_only(file)?),
+            "append" | "replace" if !file.exists() => return Ok(driver.create_vector_only(file)?),
+            "append" | "replace" => (),
+            m => bail!("Unknown mode {m:?}, use overwrite, append or replace"),
+        }
+        let multi_layer = driver
+            .metadata_item("DCAP_MULTIPLE_VECTOR_LAYERS", "")
+            .is_some();
+        let short_name = driver.short_name();
+        let data = Dataset::open_ex(
+            file,
+            DatasetOptions {
+                open_flags: GdalOpenFlags::GDAL_OF_UPDATE | GdalOpenFlags::GDAL_OF_VECTOR,
+                allowed_drivers: Some(&[short_name.as_str()]),
+                ..Default::default()
+            },
+        )
+        .context(format!(
+            "Could not open {file:?} for update with {short_name} driver"
+        ))?;
+        let existing = data.layers().position(|l| l.name() == layer);
+        if !multi_layer {
+            // single layer formats (e.g. shapefile) can only be replaced
+            if mode == "replace" && (existing.is_some() || data.layer_count() == 0) {
+                drop(data);
+                return Ok(driver.create_vector_only(file)?);
+            }
+            bail!(
+                "Driver {short_name} only supports one layer per file, \
+                 use a different file or a multi layer format like GPKG"
+            );
+        }
+        match (mode, existing) {
+            ("append", Some(_)) => bail!(
+                "Layer {layer:?} already exists in {file:?}, use mode=\"replace\" to replace it"
+            ),
+            ("replace", Some(ind)) => {
+                let err = unsafe {
+                    gdal_sys::GDALDatasetDeleteLayer(data.c_dataset(), ind as std::ffi::c_int)
+                };
+                if err != gdal_sys::OGRErr::OGRERR_NONE {
+                    bail!("Driver {short_name} could not delete layer {layer:?} from {file:?}");
+                }
+            }
+            _ => (),
+        }
+        Ok(data)
+    }
+
+    fn field_to_attr(val: FieldValue) -> Option<Attribute> {
+        match val {
+            FieldValue::IntegerValue(i) => Some(Attribute::Integer(i as i64)),
+            FieldValue::Integer64Value(i) => Some(Attribute::Integer(i)),
+            FieldValue::StringValue(i) => Some(Attribute::String(RString::from(i))),
+            FieldValue::RealValue(i) => Some(Attribute::Float(i)),
+            FieldValue::DateValue(d) => Some(Attribute::Date(Date::new(
+                d.year() as u16,
+                d.month() as u8,
+                d.day() as u8,
+            ))),
+            FieldValue::DateTimeValue(d) => Some(Attribute::DateTime(d.into())),
+            FieldValue::IntegerListValue(v) => Some(Attribute::Array(
+                v.into_iter()
+                    .map(|i| Attribute::Integer(i as i64))
+                    .collect(),
+            )),
+            FieldValue::Integer64ListValue(v) => Some(Attribute::Array(
+                v.into_iter().map(Attribute::Integer).collect(),
+            )),
+            FieldValue::RealListValue(v) => Some(Attribute::Array(
+                v.into_iter().map(Attribute::Float).collect(),
+            )),
+            FieldValue::StringListValue(v) => Some(Attribute::Array(
+                v.into_iter()
+                    .map(|s| Attribute::String(RString::from(s)))
+                    .collect(),
+            )),
+        }
+    }
+
+    /// Type of the field value, looking into the content of strings
+    fn value_kind(val: &FieldValue) -> &'static str {
+        match val {
+            FieldValue::IntegerValue(_) | FieldValue::Integer64Value(_) => "Integer",
+            FieldValue::RealValue(_) => "Float",
+            FieldValue::DateValue(_) => "Date",
+            FieldValue::DateTimeValue(_) => "DateTime",
+            FieldValue::StringValue(s) => {
+                let s = s.trim();
+                if s.parse::<i64>().is_ok() {
+                    "Integer"
+                } else if s.parse::<f64>().is_ok() {
+                    "Float"
+                } else {
+                    "String"
+                }
+            }
+            _ => "List",
+        }
+    }
+
+    /// Parse the date time, or date with time at midnight
+    fn parse_datetime(value: &str, fmt: &str) -> Result<chrono::DateTime<chrono::FixedOffset>> {
+        let dt = match chrono::NaiveDateTime::parse_from_str(value, fmt) {
+            Ok(dt) => dt,
+            Err(_) => chrono::NaiveDate::parse_from_str(value, fmt)
+                .context(format!("Could not parse {value:?} with format {fmt:?}"))?
+                .and_time(chrono::NaiveTime::MIN),
+        };
+        Ok(dt.and_utc().fixed_offset())
+    }
+
+    /// Geometry of the node from the WKT in the `geometry` attribute
+    fn node_geometry(node: &NodeInner, geometry: &str) -> Result<Geometry> {
+        let geom = String::try_from_attr(node.attr(geometry).context(format!(
+            "Attribute for geometry not found in {}",
+            node.name()
+        ))?)
+        .map_err(nadi_core::anyhow::Error::msg)?;
+        Ok(Geometry::from_wkt(&geom)?)
+    }
+
+    /// Sum of the values for the node and all nodes upstream of it
+    fn upstream_sum(node: &NodeInner, values: &HashMap<String, f64>) -> f64 {
+        values.get(node.name()).copied().unwrap_or(0.0)
+            + node
+                .inputs()
+                .iter()
+                .map(|i| upstream_sum(&i.lock(), values))
+                .sum::<f64>()
+    }
+
+    /// Minimum distance between the two geometries
+    fn geometry_distance(g1: &Geometry, g2: &Geometry) -> Result<f64> {
+        let d = unsafe { gdal_sys::OGR_G_Distance(g1.c_geometry(), g2.c_geometry()) };
+        // GDAL returns -1 when the distance can't be computed
+        if d < 0.0 {
+            bail!(
+                "Could not calculate the distance between {} and {}",
+                g1.geometry_name(),
+                g2.geometry_name()
+            );
+        }
+        Ok(d)
+    }
+
+    /// Network attribute with the CRS of the `geometry` attribute
+    fn crs_attr(geometry: &str) -> String {
+        format!("{geometry}_CRS")
+    }
+
+    /// Spatial reference from its definition (EPSG:4326, WKT, etc)
+    fn spatial_ref(def: &str) -> Result<SpatialRef> {
+        let mut srs = SpatialRef::from_definition(def).context(format!("Invalid CRS {def:?}"))?;
+        srs.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+        Ok(srs)
+    }
+
+    /// Transformation from the network geometries to the layer's CRS
+    fn layer_transform(
+        net: &Network,
+        geometry: &str,
+        lyr: &Layer,
+    ) -> Result<Option<CoordTransform>> {
+        Ok(match (net.attr(&crs_attr(geometry)), lyr.spatial_ref()) {
+            (Some(src), Some(mut dst)) => {
+                dst.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+                let src = spatial_ref(
+                    &String::try_from_attr(src).map_err(nadi_core::anyhow::Error::msg)?,
+                )?;
+                Some(CoordTransform::new(&src, &dst)?)
+            }
+            _ => None,
+        })
+    }
+
+    /// Geometry to use as the spatial filter for the layer
+    ///
+    /// When multiple filters are given, their intersection is used.
+    fn spatial_filter(
+        net: &Network,
+        geometry: &str,
+        lyr: &Layer,
+        bbox: Option<Vec<f64>>,
+        within: Option<PathBuf>,
+        network_extent: bool,
+    ) -> Result<Option<Geometry>> {
+        let mut filters: Vec<Geometry> = Vec::new();
+        if let Some(bbox) = bbox {
+            let [xmin, ymin, xmax, ymax] = bbox[..] else {
+                bail!("bbox should be [xmin, ymin, xmax, ymax], got {bbox:?}");
+            };
+            filters.push(Geometry::bbox(xmin, ymin, xmax, ymax)?);
+        }
+        if let Some(within) = within {
+            let data = Dataset::open(&within)?;
+            let mut poly_lyr = open_layer(&data, None)?;
+            let transform = match (poly_lyr.spatial_ref(), lyr.spatial_ref()) {
+                (Some(mut src), Some(mut dst)) => {
+                    src.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+                    dst.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+                    Some(CoordTransform::new(&src, &dst)?)
+                }
+                _ => None,
+            };
+            let mut union: Option<Geometry> = None;
+            for f in poly_lyr.features() {
+                let Some(g) = f.geometry() else {
+                    continue;
+                };
+                union = match union {
+                    Some(u) => u.union(g),
+                    None => Some(g.clone()),
+                };
+            }
+            let mut union = union.context(format!("No polygons found in {within:?}"))?;
+            if let Some(ct) = &transform {
+                union.transform_inplace(ct)?;
+            }
+            filters.push(union);
+        }
+        if network_extent {
+            let transform = layer_transform(net, geometry, lyr)?;
+            let mut extent: Option<(f64, f64, f64, f64)> = None;
+            for node in net.nodes() {
+                let n = node.lock();
+                if n.attr(geometry).is_none() {
+                    continue;
+                }
+                let mut g = node_geometry(&n, geometry)?;
+                if let Some(ct) = &transform {
+                    g.transform_inplace(ct)?;
+                }
+                let e = g.envelope();
+                extent = Some(match extent {
+                    Some((x1, y1, x2, y2)) => (
+                        x1.min(e.MinX),
+                        y1.min(e.MinY),
+                        x2.max(e.MaxX),
+                        y2.max(e.MaxY),
+                    ),
+                    None => (e.MinX, e.MinY, e.MaxX, e.MaxY),
+                });
+            }
+            let (x1, y1, x2, y2) =
+                extent.context("No node geometries found for the network extent")?;
+            filters.push(Geometry::bbox(x1, y1, x2, y2)?);
+        }
+        let mut filters = filters.into_iter();
+        let Some(mut filter) = filters.next() else {
+            return Ok(None);
+        };
+        for f in filters {
+            filter = filter
+                .intersection(&f)
+                .context("Spatial filters do not intersect")?;
+        }
+        Ok(Some(filter))
+    }
+
+    /// Warn if the network geometries are in a geographic CRS
+    fn warn_geographic(net: &Network, geometry: &str) -> Result<()> {
+        if let Some(crs) = net.attr(&crs_attr(geometry)) {
+            let crs = String::try_from_attr(crs).map_err(nadi_core::anyhow::Error::msg)?;
+            if spatial_ref(&crs)?.is_geographic() {
+                eprintln!("WARN Geometries are in geographic CRS, distances will be in degrees");
+            }
+        }
+        Ok(())
+    }
+
+    /// CRS of the output layer, and the transformation to reach it
+    fn output_crs(
+        net: &Network,
+        geometry: &str,
+        target_crs: Option<String>,
+    ) -> Result<(Option<SpatialRef>, Option<CoordTransform>)> {
+        let src = match net.attr(&crs_attr(geometry)) {
+            Some(a) => Some(spatial_ref(
+                &String::try_from_attr(a).map_err(nadi_core::anyhow::Error::msg)?,
+            )?),
+            None => None,
+        };
+        Ok(match (src, target_crs) {
+            (src, None) => (src, None),
+            (Some(src), Some(t)) => {
+                let t = spatial_ref(&t)?;
+                let ct = CoordTransform::new(&src, &t)?;
+                (Some(t), Some(ct))
+            }
+            (None, Some(_)) => bail!(
+                "CRS of the geometries unknown, network attribute {} not found",
+                crs_attr(geometry)
+            ),
+        })
+    }
+
     fn sanitize_key(k: &str) -> String {
         k.replace(' ', "_")
     }
 
-    type Attr2FieldValue = fn(&Attribute) -> FieldValue;
+    type Attr2FieldValue = fn(&Attribute) -> Option<FieldValue>;
 
     fn type_name_to_field(name: &str) -> Result<(u32, Attr2FieldValue), String> {
         Ok(match name {
             // This is a string that can be parsed back into correct Attribute
             "Attribute" => (OGRFieldType::OFTString, |a| {
-                FieldValue::StringValue(a.to_string())
+                Some(FieldValue::StringValue(a.to_string()))
+            }),
+            "Json" => (OGRFieldType::OFTString, |a| {
+                Some(FieldValue::StringValue(attr_to_json(a)))
             }),
             "String" => (OGRFieldType::OFTString, |a| {
-                let val: String = FromAttributeRelaxed::from_attr_relaxed(a).unwrap_or_default();
-                FieldValue::StringValue(val)
+                String::from_attr_relaxed(a).map(FieldValue::StringValue)
             }),
             "Integer" => (OGRFieldType::OFTInteger64, |a| {
-                let val: i64 = FromAttributeRelaxed::from_attr_relaxed(a).unwrap_or_default();
-                FieldValue::Integer64Value(val)
+                i64::from_attr_relaxed(a).map(FieldValue::Integer64Value)
             }),
             "Float" => (OGRFieldType::OFTReal, |a| {
-                let val: f64 = FromAttributeRelaxed::from_attr_relaxed(a).unwrap_or_default();
-                FieldValue::RealValue(val)
+                f64::from_attr_relaxed(a).map(FieldValue::RealValue)
             }),
             "Date" => (OGRFieldType::OFTDate, |a| {
-                let val: Date = FromAttributeRelaxed::from_attr_relaxed(a).unwrap_or_default();
-                FieldValue::DateValue(val.into())
+                Date::from_attr_relaxed(a).map(|v| FieldValue::DateValue(v.into()))
             }),
             // // There is no FieldValue::TimeValue
             // "Time" => (OGRFieldType::OFTTime, |a| {
@@ -270,15 +1689,80 @@ mod nadi_gis {
             //     FieldValue::TimeValue(val.into())
             // }),
             "DateTime" => (OGRFieldType::OFTDateTime, |a| {
-                let val: DateTime = FromAttributeRelaxed::from_attr_relaxed(a).unwrap_or_default();
-                FieldValue::DateTimeValue(val.into())
+                DateTime::from_attr_relaxed(a).map(|v| FieldValue::DateTimeValue(v.into()))
             }),
             // There are other types supported by gdal, that could exist as Attribute, but let's ignore them
             t => {
                 return Err(format!(
-                "Type {t} Not supported. Use String, Integer, Float, Date, DateTime or Attribute"
+                "Type {t} Not supported. Use String, Integer, Float, Date, DateTime, Json or Attribute"
             ))
             }
         })
     }
+
+    /// Type name (for `type_name_to_field`) that can hold all the values
+    fn infer_field_type(values: impl Iterator<Item = Attribute>) -> &'static str {
+        let mut ty: Option<&'static str> = None;
+        for v in values {
+            let t = match v {
+                Attribute::Bool(_) | Attribute::Integer(_) => "Integer",
+                Attribute::Float(_) => "Float",
+                Attribute::String(_) => "String",
+                Attribute::Date(_) => "Date",
+                Attribute::Time(_) => "Attribute",
+                Attribute::DateTime(_) => "DateTime",
+                Attribute::Array(_) | Attribute::Table(_) => "Json",
+            };
+            ty = Some(match (ty, t) {
+                (None, t) => t,
+                (Some(a), b) if a == b => a,
+                (Some("Integer"), "Float") | (Some("Float"), "Integer") => "Float",
+                (Some("Date"), "DateTime") | (Some("DateTime"), "Date") => "DateTime",
+                // mixed types are saved as string that can be parsed back
+                _ => "Attribute",
+            });
+        }
+        ty.unwrap_or("String")
+    }
+
+    fn attr_to_json(attr: &Attribute) -> String {
+        match attr {
+            Attribute::Bool(b) => b.to_string(),
+            Attribute::Integer(i) => i.to_string(),
+            Attribute::Float(f) if f.is_finite() => f.to_string(),
+            Attribute::Float(_) => "null".to_string(),
+            Attribute::String(s) => json_string(s),
+            Attribute::Array(a) => {
+                let vals: Vec<String> = a.iter().map(attr_to_json).collect();
+                format!("[{}]", vals.join(","))
+            }
+            Attribute::Table(t) => {
+                let mut vals: Vec<String> = t
+                    .iter()
+                    .map(|kv| format!("{}:{}", json_string(kv.0), attr_to_json(kv.1)))
+                    .collect();
+                vals.sort();
+                format!("{{{}}}", vals.join(","))
+            }
+            a => json_string(&a.to_string()),
+        }
+    }
+
+    fn json_string(s: &str) -> String {
+        let mut out = String::with_capacity(s.len() + 2);
+        out.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                c => out.push(c),
+            }
+        }
+        out.push('"');
+        out
+    }
 }
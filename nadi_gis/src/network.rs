@@ -0,0 +1,293 @@
+use gdal::errors::Result;
+use gdal::spatial_ref::{AxisMappingStrategy, CoordTransform, SpatialRef};
+use gdal::vector::Geometry;
+use std::collections::{HashMap, HashSet};
+
+pub type Point = (f64, f64);
+
+/// Vertices of all the line parts in the geometry
+///
+/// LineString gives a single part, while MultiLineString (and
+/// geometry collections) give one part for each of their lines.
+pub fn line_parts(geom: &Geometry) -> Vec<Vec<Point>> {
+    if geom.geometry_count() == 0 {
+        let pts: Vec<Point> = geom
+            .get_point_vec()
+            .into_iter()
+            .map(|(x, y, _)| (x, y))
+            .collect();
+        if pts.is_empty() {
+            vec![]
+        } else {
+            vec![pts]
+        }
+    } else {
+        (0..geom.geometry_count())
+            .flat_map(|i| line_parts(&geom.get_geometry(i)))
+            .collect()
+    }
+}
+
+/// Location of a point snapped to the stream network
+#[derive(Debug, Clone)]
+pub struct Snap {
+    /// Index of the reach the point is snapped to
+    pub reach: usize,
+    /// Segment index plus the fraction along that segment
+    pub pos: f64,
+    /// Snapped location on the reach
+    pub point: Point,
+    /// Distance from the original point to the snapped location
+    pub dist: f64,
+}
+
+/// Reaches of a stream network, digitized in the flow direction
+pub struct StreamNetwork {
+    reaches: Vec<Vec<Point>>,
+    downstream: Vec<Option<usize>>,
+}
+
+/// Transformation of the points to the CRS of the stream lines
+///
+/// `None` if both are in the same CRS, or if either of them doesn't
+/// have one, in which case they are assumed to be the same.
+pub fn points_transform(
+    points: Option<SpatialRef>,
+    streams: Option<SpatialRef>,
+) -> Result<Option<CoordTransform>> {
+    let (Some(mut points), Some(mut streams)) = (points, streams) else {
+        return Ok(None);
+    };
+    points.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+    streams.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+    if points == streams {
+        return Ok(None);
+    }
+    CoordTransform::new(&points, &streams).map(Some)
+}
+
+fn point_key(p: Point) -> (i64, i64) {
+    ((p.0 * 1e6).round() as i64, (p.1 * 1e6).round() as i64)
+}
+
+fn distance(p1: Point, p2: Point) -> f64 {
+    ((p1.0 - p2.0).powi(2) + (p1.1 - p2.1).powi(2)).sqrt()
+}
+
+impl StreamNetwork {
+    /// Connect the reaches where the last point of one is the first
+    /// point of the other; on divergence the first reach is taken.
+    pub fn new(reaches: Vec<Vec<Point>>) -> Self {
+        let mut starts: HashMap<(i64, i64), usize> = HashMap::new();
+        for (i, r) in reaches.iter().enumerate() {
+            starts.entry(point_key(r[0])).or_insert(i);
+        }
+        let downstream = reaches
+            .iter()
+            .enumerate()
+            .map(|(i, r)| {
+                starts
+                    .get(&point_key(r[r.len() - 1]))
+                    .copied()
+                    .filter(|d| *d != i)
+            })
+            .collect();
+        Self {
+            reaches,
+            downstream,
+        }
+    }
+
+    pub fn reaches_count(&self) -> usize {
+        self.reaches.len()
+    }
+
+    /// Snap the point to the nearest reach within the tolerance
+    pub fn snap(&self, pt: Point, tolerance: f64) -> Option<Snap> {
+        let mut best: Option<Snap> = None;
+        for (r, reach) in self.reaches.iter().enumerate() {
+            for (s, seg) in reach.windows(2).enumerate() {
+                let (a, b) = (seg[0], seg[1]);
+                let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+                let len2 = dx * dx + dy * dy;
+                let t = if len2 == 0.0 {
+                    0.0
+                } else {
+                    (((pt.0 - a.0) * dx + (pt.1 - a.1) * dy) / len2).clamp(0.0, 1.0)
+                };
+                let proj = (a.0 + t * dx, a.1 + t * dy);
+                let dist = distance(pt, proj);
+                if dist <= tolerance && best.as_ref().map(|b| dist < b.dist).unwrap_or(true) {
+                    best = Some(Snap {
+                        reach: r,
+                        pos: s as f64 + t,
+                        point: proj,
+                        dist,
+                    });
+                }
+            }
+            if reach.len() == 1 {
+                let dist = distance(pt, reach[0]);
+                if dist <= tolerance && best.as_ref().map(|b| dist < b.dist).unwrap_or(true) {
+                    best = Some(Snap {
+                        reach: r,
+                        pos: 0.0,
+                        point: reach[0],
+                        dist,
+                    });
+                }
+            }
+        }
+        best
+    }
+
+    /// Find the next snapped point downstream of each snapped point
+    ///
+    /// Returns the index of the downstream point along with the path
+    /// taken through the stream network to reach it.
+    pub fn trace(&self, snaps: &[Snap]) -> Vec<Option<(usize, Vec<Point>)>> {
+        let mut on_reach: HashMap<usize, Vec<(f64, usize)>> = HashMap::new();
+        for (i, s) in snaps.iter().enumerate() {
+            on_reach.entry(s.reach).or_default().push((s.pos, i));
+        }
+        for pts in on_reach.values_mut() {
+            pts.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        }
+        snaps
+            .iter()
+            .enumerate()
+            .map(|(i, snap)| {
+                let mut path = vec![snap.point];
+                let mut reach = snap.reach;
+                let mut from = Some((snap.pos, i));
+                let mut visited = HashSet::new();
+                loop {
+                    let pts = &self.reaches[reach];
+                    let start_vertex = from.map(|(p, _)| p.floor() as usize + 1).unwrap_or(0);
+                    let next = on_reach.get(&reach).and_then(|v| {
+                        v.iter()
+                            .find(|(p, j)| match from {
+                                Some((fp, fi)) => (*p, *j) > (fp, fi),
+                                None => true,
+                            })
+                            .copied()
+                    });
+                    if let Some((p, j)) = next {
+                        let end_vertex = p.floor() as usize;
+                        if start_vertex <= end_vertex {
+                            path.extend_from_slice(&pts[start_vertex..=end_vertex]);
+                        }
+                        path.push(snaps[j].point);
+                        path.dedup();
+                        return Some((j, path));
+                    }
+                    path.extend_from_slice(&pts[start_vertex.min(pts.len())..]);
+                    visited.insert(reach);
+                    match self.downstream[reach] {
+                        Some(d) if !visited.contains(&d) => {
+                            reach = d;
+                            from = None;
+                        }
+                        _ => return None,
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snap_within_tolerance() {
+        let streams = StreamNetwork::new(vec![vec![(0.0, 0.0), (4.0, 0.0), (4.0, 4.0)]]);
+        let snap = streams.snap((5.0, 2.0), 1.5).unwrap();
+        assert_eq!(snap.reach, 0);
+        assert_eq!(snap.pos, 1.5);
+        assert_eq!(snap.point, (4.0, 2.0));
+        assert_eq!(snap.dist, 1.0);
+        assert!(streams.snap((6.0, 2.0), 1.5).is_none());
+    }
+
+    #[test]
+    fn trace_confluence() {
+        let streams = StreamNetwork::new(vec![
+            vec![(0.0, 4.0), (0.0, 2.0), (2.0, 2.0)],
+            vec![(2.0, 4.0), (2.0, 2.0)],
+            vec![(2.0, 2.0), (2.0, 0.0)],
+        ]);
+        let snaps: Vec<Snap> = [(0.0, 3.0), (2.0, 3.5), (2.0, 1.0)]
+            .into_iter()
+            .map(|p| streams.snap(p, 0.1).unwrap())
+            .collect();
+        assert_eq!(
+            snaps.iter().map(|s| s.reach).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        let down = streams.trace(&snaps);
+        assert_eq!(
+            down[0],
+            Some((2, vec![(0.0, 3.0), (0.0, 2.0), (2.0, 2.0), (2.0, 1.0)]))
+        );
+        assert_eq!(down[1], Some((2, vec![(2.0, 3.5), (2.0, 2.0), (2.0, 1.0)])));
+        assert_eq!(down[2], None);
+    }
+
+    #[test]
+    fn trace_same_reach() {
+        let streams = StreamNetwork::new(vec![vec![(0.0, 0.0), (4.0, 0.0), (4.0, 4.0)]]);
+        // downstream point given first
+        let snaps: Vec<Snap> = [(4.0, 2.0), (1.0, 0.0)]
+            .into_iter()
+            .map(|p| streams.snap(p, 0.1).unwrap())
+            .collect();
+        let down = streams.trace(&snaps);
+        assert_eq!(down[0], None);
+        assert_eq!(down[1], Some((0, vec![(1.0, 0.0), (4.0, 0.0), (4.0, 2.0)])));
+
+        // both points on the same segment
+        let snaps: Vec<Snap> = [(1.0, 0.0), (3.0, 0.0)]
+            .into_iter()
+            .map(|p| streams.snap(p, 0.1).unwrap())
+            .collect();
+        let down = streams.trace(&snaps);
+        assert_eq!(down[0], Some((1, vec![(1.0, 0.0), (3.0, 0.0)])));
+        assert_eq!(down[1], None);
+    }
+
+    #[test]
+    fn snap_reprojected_points() {
+        let wgs84 = || SpatialRef::from_epsg(4326).unwrap();
+        let mercator = || SpatialRef::from_epsg(3857).unwrap();
+        assert!(points_transform(Some(wgs84()), Some(wgs84()))
+            .unwrap()
+            .is_none());
+        assert!(points_transform(Some(wgs84()), None).unwrap().is_none());
+
+        // 1 degree of longitude along the equator in web mercator
+        let x = 6378137.0 * std::f64::consts::PI / 180.0;
+        let streams = StreamNetwork::new(vec![vec![(x, 1000.0), (x, -1000.0)]]);
+        let mut pt = Geometry::from_wkt("POINT (1 0)").unwrap();
+        assert!(streams.snap((1.0, 0.0), 1.0).is_none());
+        let ct = points_transform(Some(wgs84()), Some(mercator()))
+            .unwrap()
+            .unwrap();
+        pt.transform_inplace(&ct).unwrap();
+        let (px, py, _) = pt.get_point(0);
+        let snap = streams.snap((px, py), 1.0).unwrap();
+        assert_eq!(snap.reach, 0);
+        assert!(snap.dist < 1e-3);
+    }
+
+    #[test]
+    fn trace_loop() {
+        let streams = StreamNetwork::new(vec![
+            vec![(0.0, 0.0), (1.0, 0.0)],
+            vec![(1.0, 0.0), (0.0, 0.0)],
+        ]);
+        let snaps = vec![streams.snap((0.5, 0.0), 0.1).unwrap()];
+        assert_eq!(streams.trace(&snaps), vec![None]);
+    }
+}